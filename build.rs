@@ -3,36 +3,480 @@ use cmake;
 #[cfg(feature = "use-system-lib")]
 use pkg_config;
 
-#[cfg(feature = "build-from-src")]
-fn build_keystone() {
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Architecture features, along with the name of the corresponding LLVM targets.
+const ARCH_FEATURES: &[(&str, &str)] = &[
+    ("ARCH_ARM", "ARM"),
+    ("ARCH_ARM64", "AArch64"),
+    ("ARCH_EVM", "EVM"),
+    ("ARCH_HEXAGON", "Hexagon"),
+    ("ARCH_MIPS", "Mips"),
+    ("ARCH_PPC", "PowerPC"),
+    ("ARCH_SPARC", "Sparc"),
+    ("ARCH_SYSTEMZ", "SystemZ"),
+    ("ARCH_X86", "X86"),
+];
+
+/// Returns the LLVM targets selected through the `arch-*` features, or `None` if no architecture
+/// was selected.
+fn llvm_targets() -> Option<String> {
+    let targets: Vec<&str> = ARCH_FEATURES
+        .iter()
+        .filter(|(feature, _)| std::env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some())
+        .map(|&(_, target)| target)
+        .collect();
+    if targets.is_empty() {
+        None
+    } else {
+        Some(targets.join(";"))
+    }
+}
+
+/// Keystone API versions supported by the bindings, as `(major, minor)`, which must match
+/// `version::Version::MIN_SUPPORTED` and `version::Version::MAX_SUPPORTED`.
+const API_VERSIONS: std::ops::RangeInclusive<(u32, u32)> = (0, 9)..=(0, 9);
+
+/// Returns `true` if the bindings support the Keystone API version `(major, minor)`.
+fn version_supported(major: u32, minor: u32) -> bool {
+    API_VERSIONS.contains(&(major, minor))
+}
+
+/// Describes the API versions supported by the bindings, for error messages.
+fn supported_versions() -> String {
+    let (&(min_major, min_minor), &(max_major, max_minor)) =
+        (API_VERSIONS.start(), API_VERSIONS.end());
+    if API_VERSIONS.start() == API_VERSIONS.end() {
+        format!("{}.{}", min_major, min_minor)
+    } else {
+        format!("{}.{} to {}.{}", min_major, min_minor, max_major, max_minor)
+    }
+}
+
+/// Environment variable giving the directory of a prebuilt Keystone library.
+const ENV_LIB_DIR: &str = "KEYSTONE_LIB_DIR";
+/// Environment variable giving the directory of the Keystone headers.
+const ENV_INCLUDE_DIR: &str = "KEYSTONE_INCLUDE_DIR";
+/// Environment variable selecting static (`1`) or dynamic (`0`) linking.
+const ENV_STATIC: &str = "KEYSTONE_STATIC";
+/// Environment variable giving the directory caching the libraries built from source.
+#[cfg(all(feature = "build-from-src", not(feature = "use-system-lib")))]
+const ENV_PREBUILT: &str = "KEYSTONE_PREBUILT";
+
+/// Returns the value of the environment variable `name`, and asks cargo to rerun the build
+/// script when it changes.
+fn env(name: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed={}", name);
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Links the C++ runtime required by a static Keystone library.
+fn link_cxx_runtime() {
+    let target = std::env::var("TARGET").unwrap();
+    if target.contains("apple") {
+        println!("cargo:rustc-link-lib=dylib=c++");
+    } else if target.contains("linux") {
+        println!("cargo:rustc-link-lib=dylib=stdc++");
+    } else if target.contains("windows") {
+        println!("cargo:rustc-link-lib=dylib=shell32");
+    }
+}
+
+/// Extracts the API version from the `keystone/keystone.h` header in `include_dir`.
+fn header_version(include_dir: &Path) -> Option<(u32, u32)> {
+    let path = include_dir.join("keystone").join("keystone.h");
+    println!("cargo:rerun-if-changed={}", path.display());
+    let header = std::fs::read_to_string(path).ok()?;
+    let define = |name: &str| {
+        header.lines().find_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("#define"), Some(n), Some(v)) if n == name => v.parse().ok(),
+                _ => None,
+            }
+        })
+    };
+    Some((define("KS_API_MAJOR")?, define("KS_API_MINOR")?))
+}
+
+/// Returns the architecture, as named by `CARGO_CFG_TARGET_ARCH`, of an ELF or Mach-O object.
+fn object_arch(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"\x7fELF") && header.len() >= 20 {
+        let machine = if header[5] == 2 {
+            u16::from_be_bytes([header[18], header[19]])
+        } else {
+            u16::from_le_bytes([header[18], header[19]])
+        };
+        // MIPS and RISC-V use the same machine for 32 and 64 bits, told apart by the class.
+        let is_64 = header[4] == 2;
+        return match machine {
+            3 => Some("x86"),
+            8 if is_64 => Some("mips64"),
+            8 => Some("mips"),
+            20 => Some("powerpc"),
+            21 => Some("powerpc64"),
+            40 => Some("arm"),
+            62 => Some("x86_64"),
+            183 => Some("aarch64"),
+            243 if is_64 => Some("riscv64"),
+            243 => Some("riscv32"),
+            _ => None,
+        };
+    }
+    if header.len() >= 8 {
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic == 0xfeedface || magic == 0xfeedfacf {
+            return match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+                0x7 => Some("x86"),
+                0x0100_0007 => Some("x86_64"),
+                0xc => Some("arm"),
+                0x0100_000c => Some("aarch64"),
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+/// Returns the architecture of the library at `path`, either a shared library or a static
+/// archive, in which case the first object member is used.
+fn library_arch(path: &Path) -> Option<&'static str> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0u8; 20];
+    file.read_exact(&mut header[..8]).ok()?;
+    if &header[..8] != b"!<arch>\n" {
+        file.read_exact(&mut header[8..]).ok()?;
+        return object_arch(&header);
+    }
+    // Walks the members of the archive, each of which is preceded by a 60-byte header whose
+    // bytes 48 to 58 hold its size in decimal.
+    let mut member = [0u8; 60];
+    while file.read_exact(&mut member).is_ok() {
+        let size: u64 = std::str::from_utf8(&member[48..58])
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        let start = file.stream_position().ok()?;
+        if size >= 20 {
+            file.read_exact(&mut header).ok()?;
+            if let Some(arch) = object_arch(&header) {
+                return Some(arch);
+            }
+        }
+        // Members are aligned on two bytes.
+        file.seek(SeekFrom::Start(start + size + size % 2)).ok()?;
+    }
+    None
+}
+
+/// Returns the file name of the Keystone library for the target.
+fn library_name(static_lib: bool) -> &'static str {
+    let os = std::env::var("CARGO_CFG_TARGET_OS").unwrap();
+    if std::env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc") {
+        // Dynamic libraries are linked through their import library.
+        "keystone.lib"
+    } else if static_lib {
+        "libkeystone.a"
+    } else if os == "macos" || os == "ios" {
+        "libkeystone.dylib"
+    } else if os == "windows" {
+        "libkeystone.dll.a"
+    } else {
+        "libkeystone.so"
+    }
+}
+
+/// Checks that the Keystone library at `path`, and its headers in `include_dir` if any, match
+/// the bindings and the target.
+fn check_library(path: &Path, include_dir: Option<&Path>) {
+    if !path.exists() {
+        panic!("Could not find the Keystone library at {}", path.display());
+    }
+    println!("cargo:rerun-if-changed={}", path.display());
+    let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    if let Some(arch) = library_arch(path) {
+        if arch != target_arch {
+            panic!(
+                "{} was built for {}, but the target architecture is {}",
+                path.display(),
+                arch,
+                target_arch
+            );
+        }
+    }
+    if let Some((major, minor)) = include_dir.and_then(header_version) {
+        if !version_supported(major, minor) {
+            panic!(
+                "Keystone headers in {} are for version {}.{}, but the bindings support version \
+                 {}",
+                include_dir.unwrap().display(),
+                major,
+                minor,
+                supported_versions()
+            );
+        }
+    }
+}
+
+/// Links against the prebuilt Keystone library in `lib_dir`.
+///
+/// The library is linked statically if `KEYSTONE_STATIC` is set to `1` and dynamically if it's
+/// set to `0`. If it's unset or empty, the library is linked statically only when no dynamic
+/// library is available. Any other value is rejected. The headers are looked up in
+/// `include_dir`, or in the `include` directory next to `lib_dir`, which is returned if it
+/// exists.
+fn link_prebuilt(lib_dir: &Path, include_dir: Option<&Path>) -> Option<PathBuf> {
+    let static_lib = match env(ENV_STATIC).as_deref() {
+        Some("0") => false,
+        Some("1") => true,
+        Some(value) => panic!("{} must be set to 0 or 1, not `{}`", ENV_STATIC, value),
+        None => !lib_dir.join(library_name(false)).exists(),
+    };
+    let include_dir = include_dir.map(Path::to_path_buf).or_else(|| {
+        let dir = lib_dir.parent()?.join("include");
+        dir.exists().then_some(dir)
+    });
+    check_library(
+        &lib_dir.join(library_name(static_lib)),
+        include_dir.as_deref(),
+    );
+    println!("cargo:rustc-link-search=native={}", lib_dir.display());
+    if static_lib {
+        println!("cargo:rustc-link-lib=static=keystone");
+        link_cxx_runtime();
+    } else {
+        println!("cargo:rustc-link-lib=dylib=keystone");
+    }
+    include_dir
+}
+
+/// Copies the files in `src` to `dst` recursively.
+#[cfg(all(feature = "build-from-src", not(feature = "use-system-lib")))]
+fn copy_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let path = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &path)?;
+        } else {
+            std::fs::copy(entry.path(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds Keystone from source and links against it, returning the directory of its headers.
+#[cfg(all(feature = "build-from-src", not(feature = "use-system-lib")))]
+fn link_default() -> Option<PathBuf> {
+    // Reuses a previous build from the cache if there is one. Entries are keyed by target and
+    // selected architectures, since both change the resulting library.
+    let target = std::env::var("TARGET").unwrap();
+    let targets = llvm_targets();
+    let cache = env(ENV_PREBUILT).map(|dir| {
+        let archs = targets.as_deref().unwrap_or("all").replace(';', "-");
+        PathBuf::from(dir).join(format!("{}-{}", target, archs.to_lowercase()))
+    });
+    if let Some(cache) = &cache {
+        if cache.join("lib").exists() {
+            return link_prebuilt(&cache.join("lib"), Some(&cache.join("include")));
+        }
+    }
+
     let dest = cmake::Config::new("keystone")
         .define("CMAKE_INSTALL_LIBDIR", "lib")
         .define("BUILD_LIBS_ONLY", "1")
         .define("BUILD_SHARED_LIBS", "OFF")
-        .define("LLVM_TARGETS_TO_BUILD", "all")
+        .define(
+            "LLVM_TARGETS_TO_BUILD",
+            targets.unwrap_or_else(|| "all".to_string()),
+        )
         // Prevent python from leaving behind `.pyc` files which break `cargo package`
         .env("PYTHONDONTWRITEBYTECODE", "1")
         .build();
 
+    if let Some(cache) = &cache {
+        // The cache is filled in a temporary directory first, so that concurrent builds never
+        // see a partial entry.
+        let tmp = cache.with_extension(format!("tmp{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmp);
+        let copied = ["lib", "include"].iter().all(|dir| {
+            copy_dir(&dest.join(dir), &tmp.join(dir))
+                .map_err(|e| println!("cargo:warning=Could not fill the Keystone cache: {}", e))
+                .is_ok()
+        });
+        // A partial entry is never published, later builds would trust it.
+        if !copied || std::fs::rename(&tmp, cache).is_err() {
+            let _ = std::fs::remove_dir_all(&tmp);
+        }
+    }
+
     println!("cargo:rustc-link-search=native={}/lib", dest.display());
     println!("cargo:rustc-link-lib=keystone");
+    link_cxx_runtime();
+    Some(dest.join("include"))
+}
 
-    let target = std::env::var("TARGET").unwrap();
-    if target.contains("apple") {
-        println!("cargo:rustc-link-lib=dylib=c++");
-    } else if target.contains("linux") {
-        println!("cargo:rustc-link-lib=dylib=stdc++");
-    } else if target.contains("windows") {
-        println!("cargo:rustc-link-lib=dylib=shell32");
+/// Links against the system Keystone library, returning the directory of its headers.
+#[cfg(feature = "use-system-lib")]
+fn link_default() -> Option<PathBuf> {
+    let lib = pkg_config::find_library("keystone").expect("Could not find system keystone");
+    let mut version = lib.version.split('.').map(|n| n.parse::<u32>());
+    if let (Some(Ok(major)), Some(Ok(minor))) = (version.next(), version.next()) {
+        if !version_supported(major, minor) {
+            panic!(
+                "System Keystone version is {}, but the bindings support version {}",
+                lib.version,
+                supported_versions()
+            );
+        }
+    }
+    lib.include_paths
+        .into_iter()
+        .find(|dir| dir.join("keystone").join("keystone.h").exists())
+}
+
+/// No library to link against was selected.
+#[cfg(not(any(feature = "build-from-src", feature = "use-system-lib")))]
+fn link_default() -> Option<PathBuf> {
+    None
+}
+
+/// Removes the comments from C source code.
+fn strip_comments(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut rest = src;
+    while let Some(idx) = rest.find('/') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+        if let Some(tail) = rest.strip_prefix("//") {
+            rest = tail.find('\n').map_or("", |end| &tail[end..]);
+        } else if let Some(tail) = rest.strip_prefix("/*") {
+            rest = tail.find("*/").map_or("", |end| &tail[end + 2..]);
+            out.push(' ');
+        } else {
+            out.push('/');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Evaluates the value of an enumerator, which can reference the ones defined before it.
+fn eval_const(expr: &str, consts: &[(String, i64)]) -> Option<i64> {
+    let expr = expr.trim();
+    if let Some(inner) = expr.strip_prefix('(').and_then(|e| e.strip_suffix(')')) {
+        return eval_const(inner, consts);
+    }
+    if let Some((lhs, rhs)) = expr.split_once('|') {
+        return Some(eval_const(lhs, consts)? | eval_const(rhs, consts)?);
+    }
+    if let Some((lhs, rhs)) = expr.split_once("<<") {
+        return Some(eval_const(lhs, consts)? << eval_const(rhs, consts)?);
     }
+    let literal = expr.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = literal
+        .strip_prefix("0x")
+        .or_else(|| literal.strip_prefix("0X"))
+    {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(value) = literal.parse() {
+        return Some(value);
+    }
+    consts
+        .iter()
+        .find(|(name, _)| name == expr)
+        .map(|&(_, value)| value)
+}
+
+/// Returns the macros and enumerators with a constant value defined in the C header `src`,
+/// along with their values.
+fn header_consts(src: &str) -> Vec<(String, i64)> {
+    let src = strip_comments(src);
+    let mut consts = vec![];
+    for line in src.lines() {
+        let mut words = line.trim().splitn(3, char::is_whitespace);
+        if let (Some("#define"), Some(name), Some(expr)) =
+            (words.next(), words.next(), words.next())
+        {
+            if let Some(value) = eval_const(expr, &consts) {
+                consts.push((name.to_string(), value));
+            }
+        }
+    }
+    let mut rest = &src[..];
+    while let Some(idx) = rest.find("enum") {
+        rest = &rest[idx + 4..];
+        let (Some(start), Some(end)) = (rest.find('{'), rest.find('}')) else {
+            break;
+        };
+        if rest[..start].contains(';') || end < start {
+            continue;
+        }
+        let mut next = 0;
+        for item in rest[start + 1..end].split(',') {
+            let (name, value) = match item.split_once('=') {
+                Some((name, expr)) => match eval_const(expr, &consts) {
+                    Some(value) => (name.trim(), value),
+                    None => panic!("Could not evaluate `{}` in keystone.h", item.trim()),
+                },
+                None => (item.trim(), next),
+            };
+            if !name.is_empty() {
+                consts.push((name.to_string(), value));
+                next = value + 1;
+            }
+        }
+        rest = &rest[end..];
+    }
+    consts
+}
+
+/// Writes the constants of `keystone/keystone.h` in `include_dir` to `$OUT_DIR/keystone_h.rs`,
+/// where the bindings check them against their own definitions.
+///
+/// No constant is written if the header isn't available.
+fn write_header_consts(include_dir: Option<&Path>) {
+    let header = include_dir.map(|dir| dir.join("keystone").join("keystone.h"));
+    let consts = match header.as_ref().map(std::fs::read_to_string) {
+        Some(Ok(src)) => header_consts(&src),
+        _ => vec![],
+    };
+    let mut out = String::from("/// Constants defined in the `keystone.h` header being linked.\n");
+    out.push_str("const HEADER_CONSTS: &[(&str, i64)] = &[\n");
+    for (name, value) in consts {
+        out.push_str(&format!("    ({:?}, {}),\n", name, value));
+    }
+    out.push_str("];\n");
+    let path = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("keystone_h.rs");
+    std::fs::write(path, out).expect("Could not write the Keystone constants");
 }
 
 fn main() {
-    if cfg!(feature = "use-system-lib") {
-        #[cfg(feature = "use-system-lib")]
-        pkg_config::find_library("keystone").expect("Could not find system keystone");
-    } else {
-        #[cfg(feature = "build-from-src")]
-        build_keystone();
+    let lib_dir = env(ENV_LIB_DIR).map(PathBuf::from);
+    let include_dir = env(ENV_INCLUDE_DIR).map(PathBuf::from);
+    // Lets the tests know which architectures are expected to be supported. The `arch-*`
+    // features only apply when building Keystone from source.
+    println!("cargo:rustc-check-cfg=cfg(keystone_all_archs)");
+    if llvm_targets().is_none()
+        || cfg!(feature = "use-system-lib")
+        || cfg!(feature = "dynamic-load")
+        || lib_dir.is_some()
+    {
+        println!("cargo:rustc-cfg=keystone_all_archs");
     }
+    let headers = if cfg!(feature = "dynamic-load") {
+        // The library is opened at runtime, there is nothing to link against.
+        include_dir
+    } else if let Some(lib_dir) = lib_dir {
+        link_prebuilt(&lib_dir, include_dir.as_deref())
+    } else {
+        link_default().or(include_dir)
+    };
+    write_header_consts(headers.as_deref());
 }
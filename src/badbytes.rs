@@ -0,0 +1,515 @@
+//! Bad-byte detection and avoidance.
+//!
+//! Shellcode often has to avoid some byte values, e.g. NUL bytes when it is copied with `strcpy`
+//! or newlines when it is read with `fgets`. [`BadBytes`] represents such a set of forbidden
+//! bytes and [`Rewriter`] rewrites x86/x64 programs whose encoding contains them, by trying
+//! semantically equivalent alternatives for the offending statements:
+//!
+//!  * `mov eax, 0` becomes `xor eax, eax`;
+//!  * immediates are split, e.g. `mov eax, 0x10` becomes `mov eax, 0x01010111` followed by
+//!    `xor eax, 0x01010101`;
+//!  * immediates are loaded through the stack, e.g. `push 0x10` followed by `pop eax`.
+//!
+//! The alternatives may clobber the flags. Programs are expected to use the Intel syntax.
+//!
+//! ```no_run
+//! use keystone_engine::badbytes::{BadBytes, Rewriter};
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let rewriter = Rewriter::new(&engine, BadBytes::from(&b"\x00\x0a"[..]))
+//!     .expect("Could not create rewriter");
+//! let res = rewriter.rewrite("mov eax, 0\nret", 0).expect("Could not rewrite");
+//! assert_eq!(res.source, "xor eax, eax\nret");
+//! assert!(res.unfixable.is_empty());
+//! ```
+
+use crate::layout::{self, Statement, StatementKind};
+use crate::{ffi, Keystone, KeystoneOutput, Result};
+
+// -----------------------------------------------------------------------------------------------
+// Bad bytes
+// -----------------------------------------------------------------------------------------------
+
+/// Set of forbidden byte values.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct BadBytes([u64; 4]);
+
+impl BadBytes {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `byte` to the set.
+    pub fn insert(&mut self, byte: u8) {
+        self.0[(byte >> 6) as usize] |= 1 << (byte & 0x3f);
+    }
+
+    /// Removes `byte` from the set.
+    pub fn remove(&mut self, byte: u8) {
+        self.0[(byte >> 6) as usize] &= !(1 << (byte & 0x3f));
+    }
+
+    /// Returns `true` if `byte` is forbidden.
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0[(byte >> 6) as usize] & (1 << (byte & 0x3f)) != 0
+    }
+
+    /// Returns `true` if no byte is forbidden.
+    pub fn is_empty(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// Returns the forbidden bytes in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(move |&b| self.contains(b))
+    }
+
+    /// Returns the offset of the first forbidden byte in `bytes`, if any.
+    pub fn find(&self, bytes: &[u8]) -> Option<usize> {
+        bytes.iter().position(|&b| self.contains(b))
+    }
+
+    /// Returns `true` if `bytes` doesn't contain any forbidden byte.
+    pub fn is_clean(&self, bytes: &[u8]) -> bool {
+        self.find(bytes).is_none()
+    }
+}
+
+impl From<&[u8]> for BadBytes {
+    fn from(bytes: &[u8]) -> Self {
+        bytes.iter().copied().collect()
+    }
+}
+
+impl FromIterator<u8> for BadBytes {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut set = BadBytes::new();
+        for byte in iter {
+            set.insert(byte);
+        }
+        set
+    }
+}
+
+impl std::fmt::Debug for BadBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.iter().map(|b| format!("{:#04x}", b)))
+            .finish()
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Alternatives
+// -----------------------------------------------------------------------------------------------
+
+/// General purpose registers, grouped by family and ordered by size (64, 32, 16 and 8 bits).
+const REGISTERS: [[&str; 4]; 16] = [
+    ["rax", "eax", "ax", "al"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["rbp", "ebp", "bp", "bpl"],
+    ["rsp", "esp", "sp", "spl"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+/// Register operand.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Register {
+    /// Index of the register family in [`REGISTERS`].
+    family: usize,
+    /// Size of the register in bits.
+    bits: u32,
+}
+
+impl Register {
+    /// Parses a register name.
+    fn parse(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        if matches!(name.as_str(), "ah" | "bh" | "ch" | "dh") {
+            return Some(Register {
+                family: usize::MAX,
+                bits: 8,
+            });
+        }
+        REGISTERS.iter().enumerate().find_map(|(family, names)| {
+            let idx = names.iter().position(|&n| n == name)?;
+            Some(Register {
+                family,
+                bits: 64 >> idx,
+            })
+        })
+    }
+
+    /// Returns the name of the register with the given size.
+    fn name(self, bits: u32) -> Option<&'static str> {
+        let names = REGISTERS.get(self.family)?;
+        Some(names[(64 / bits).trailing_zeros() as usize])
+    }
+
+    /// Returns the mask of the values held by the register.
+    fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.bits)
+    }
+}
+
+/// Formats an immediate for an operand of `bits` bits.
+///
+/// 64-bit operands only accept sign-extended 32-bit immediates, so they are formatted as signed
+/// values.
+fn imm(value: u64, bits: u32) -> String {
+    if bits == 64 && (value as i64) < 0 {
+        format!("-{:#x}", (value as i64).unsigned_abs())
+    } else {
+        format!("{:#x}", value & (u64::MAX >> (64 - bits)))
+    }
+}
+
+/// Returns `true` if `value` is a valid immediate for an operand of `bits` bits.
+fn fits(value: u64, bits: u32) -> bool {
+    bits < 64 || i32::try_from(value as i64).is_ok()
+}
+
+/// Returns a key such that neither `key` nor `value ^ key` contain bad bytes, byte per byte.
+pub(crate) fn xor_key(bad: &BadBytes, value: u64, bits: u32) -> Option<u64> {
+    let mut key = 0;
+    for i in 0..bits / 8 {
+        let byte = (value >> (8 * i)) as u8;
+        let k = (1..=255u8).find(|&k| !bad.contains(k) && !bad.contains(byte ^ k))?;
+        key |= (k as u64) << (8 * i);
+    }
+    Some(key)
+}
+
+/// Returns a key such that neither `key` nor `value - key` contain bad bytes.
+fn add_key(bad: &BadBytes, value: u64, bits: u32) -> Option<u64> {
+    let mask = u64::MAX >> (64 - bits);
+    (1..=255u8)
+        .map(|b| u64::from_ne_bytes([b; 8]) & mask)
+        .find(|&k| {
+            let rest = value.wrapping_sub(k) & mask;
+            (0..bits / 8).all(|i| {
+                !bad.contains((k >> (8 * i)) as u8) && !bad.contains((rest >> (8 * i)) as u8)
+            })
+        })
+}
+
+/// Returns the alternatives of an instruction, from the most to the least preferred.
+///
+/// Every alternative is a list of statements.
+fn alternatives(bad: &BadBytes, mode: ffi::Mode, code: &str) -> Vec<Vec<String>> {
+    let code = code.trim();
+    let (mnemonic, operands) = match code.find(char::is_whitespace) {
+        Some(idx) => (code[..idx].to_ascii_lowercase(), code[idx..].trim()),
+        None => (code.to_ascii_lowercase(), ""),
+    };
+    let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+    let mut alts = vec![];
+    match (mnemonic.as_str(), operands.as_slice()) {
+        ("mov", [dst, src]) => {
+            let (reg, value) = match (Register::parse(dst), layout::parse_int(src)) {
+                (Some(reg), Some(value)) => (reg, value as u64 & reg.mask()),
+                _ => return alts,
+            };
+            // Writing a 32-bit register zero-extends it, which allows shorter immediates for
+            // 64-bit registers.
+            let (dst, bits) = match reg.name(32) {
+                Some(name) if reg.bits == 64 && value <= u32::MAX as u64 => (name, 32),
+                _ => (*dst, reg.bits),
+            };
+            if value == 0 {
+                alts.push(vec![format!("xor {}, {}", dst, dst)]);
+                alts.push(vec![format!("sub {}, {}", dst, dst)]);
+            }
+            if value == 1 {
+                alts.push(vec![
+                    format!("xor {}, {}", dst, dst),
+                    format!("inc {}", dst),
+                ]);
+            }
+            let mask = u64::MAX >> (64 - bits);
+            if value == mask {
+                alts.push(vec![
+                    format!("xor {}, {}", dst, dst),
+                    format!("dec {}", dst),
+                ]);
+                alts.push(vec![format!("or {}, -1", dst)]);
+            }
+            if fits(!value & mask, bits) {
+                alts.push(vec![
+                    format!("mov {}, {}", dst, imm(!value & mask, bits)),
+                    format!("not {}", dst),
+                ]);
+            }
+            if fits(value.wrapping_neg() & mask, bits) {
+                alts.push(vec![
+                    format!("mov {}, {}", dst, imm(value.wrapping_neg() & mask, bits)),
+                    format!("neg {}", dst),
+                ]);
+            }
+            if let Some(key) = xor_key(bad, value, bits).filter(|&k| fits(k, bits)) {
+                alts.push(vec![
+                    format!("mov {}, {}", dst, imm(value ^ key, bits)),
+                    format!("xor {}, {}", dst, imm(key, bits)),
+                ]);
+            }
+            if let Some(key) = add_key(bad, value, bits).filter(|&k| fits(k, bits)) {
+                alts.push(vec![
+                    format!("mov {}, {}", dst, imm(value.wrapping_sub(key) & mask, bits)),
+                    format!("add {}, {}", dst, imm(key, bits)),
+                ]);
+            }
+            // Pushes and pops use the stack width of the current mode.
+            let stack_bits = if mode.contains(ffi::Mode::MODE_64) {
+                64
+            } else {
+                32
+            };
+            if let Some(name) = reg.name(stack_bits) {
+                if (reg.bits == stack_bits || (reg.bits == 32 && stack_bits == 64))
+                    && value <= i32::MAX as u64
+                {
+                    alts.push(vec![format!("push {:#x}", value), format!("pop {}", name)]);
+                }
+            }
+        }
+        ("push", [src]) => {
+            let value = match layout::parse_int(src) {
+                Some(value) => value as u64 & 0xffff_ffff,
+                _ => return alts,
+            };
+            let (ptr, sp) = if mode.contains(ffi::Mode::MODE_64) {
+                ("qword", "rsp")
+            } else {
+                ("dword", "esp")
+            };
+            // The value pushed is the sign-extended 32-bit immediate, as is the xor key.
+            if let Some(key) = xor_key(bad, value, 32) {
+                alts.push(vec![
+                    format!("push {:#x}", value ^ key),
+                    format!("xor {} ptr [{}], {:#x}", ptr, sp, key),
+                ]);
+            }
+        }
+        (op @ ("add" | "sub" | "xor"), [dst, src]) => {
+            let (reg, value) = match (Register::parse(dst), layout::parse_int(src)) {
+                (Some(reg), Some(value)) => (reg, value as u64 & reg.mask()),
+                _ => return alts,
+            };
+            let bits = reg.bits;
+            let mask = reg.mask();
+            if op != "xor" && fits(value.wrapping_neg() & mask, bits) {
+                let inverse = if op == "add" { "sub" } else { "add" };
+                alts.push(vec![format!(
+                    "{} {}, {}",
+                    inverse,
+                    dst,
+                    imm(value.wrapping_neg() & mask, bits)
+                )]);
+            }
+            let key = match op {
+                "xor" => xor_key(bad, value, bits).map(|k| (value ^ k, k)),
+                _ => add_key(bad, value, bits).map(|k| (value.wrapping_sub(k) & mask, k)),
+            };
+            if let Some((a, b)) = key.filter(|&(a, b)| fits(a, bits) && fits(b, bits)) {
+                alts.push(vec![
+                    format!("{} {}, {}", op, dst, imm(a, bits)),
+                    format!("{} {}, {}", op, dst, imm(b, bits)),
+                ]);
+            }
+        }
+        _ => {}
+    }
+    alts
+}
+
+// -----------------------------------------------------------------------------------------------
+// Rewriter
+// -----------------------------------------------------------------------------------------------
+
+/// Statement whose encoding still contains forbidden bytes after rewriting.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Unfixable {
+    /// Line of the statement in the source, starting at 1.
+    pub line: usize,
+    /// Statement, as found in the rewritten source.
+    pub statement: String,
+    /// Encoding of the statement, without the zeros skipped by an `.org` directive.
+    pub bytes: Vec<u8>,
+}
+
+/// Result of a rewrite.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Rewrite {
+    /// Rewritten source.
+    pub source: String,
+    /// Encoding of the rewritten source.
+    pub output: KeystoneOutput,
+    /// Statements that couldn't be fixed.
+    pub unfixable: Vec<Unfixable>,
+}
+
+/// Rewrites x86/x64 programs to avoid forbidden bytes in their encoding.
+#[derive(Debug)]
+pub struct Rewriter<'a> {
+    /// Keystone instance used to assemble the candidates.
+    engine: &'a Keystone,
+    /// Forbidden bytes.
+    bad: BadBytes,
+}
+
+impl<'a> Rewriter<'a> {
+    /// Creates a new rewriter.
+    ///
+    /// Returns [`ffi::Error::ARCH`] if `engine` isn't an x86 instance.
+    pub fn new(engine: &'a Keystone, bad: BadBytes) -> Result<Self> {
+        if engine.arch() != ffi::Arch::X86 {
+            return Err(ffi::Error::ARCH)?;
+        }
+        Ok(Rewriter { engine, bad })
+    }
+
+    /// Rewrites `source`, assembled at `address`, until its encoding is free of bad bytes or no
+    /// alternative is left.
+    ///
+    /// Statements that can't be fixed are reported in [`Rewrite::unfixable`].
+    pub fn rewrite(&self, source: &str, address: u64) -> Result<Rewrite> {
+        let mut stmts = layout::parse(ffi::Arch::X86, source);
+        let mut lay = layout::layout(self.engine, &stmts, address, |_| None);
+        if let Some(Err(e)) = lay.placed.iter().map(|p| &p.bytes).find(|b| b.is_err()) {
+            return Err(*e);
+        }
+        // Statements that have already been rewritten, or for which no alternative worked.
+        let mut locked = vec![false; stmts.len()];
+        loop {
+            let dirty = (0..stmts.len()).find(|&i| {
+                !locked[i]
+                    && stmts[i].kind == StatementKind::Code
+                    && matches!(&lay.placed[i].bytes, Ok(b) if !self.bad.is_clean(b))
+            });
+            let idx = match dirty {
+                Some(idx) => idx,
+                None => break,
+            };
+            locked[idx] = true;
+            for alt in alternatives(&self.bad, self.engine.mode(), &stmts[idx].code) {
+                let replacement = replace(&stmts[idx], &alt);
+                let count = replacement.len();
+                let mut candidate = stmts.clone();
+                candidate.splice(idx..idx + 1, replacement);
+                let cand_lay = layout::layout(self.engine, &candidate, address, |_| None);
+                let clean = cand_lay.placed[idx..idx + count]
+                    .iter()
+                    .all(|p| matches!(&p.bytes, Ok(b) if self.bad.is_clean(b)));
+                if clean {
+                    stmts = candidate;
+                    lay = cand_lay;
+                    locked.splice(idx..idx + 1, std::iter::repeat_n(true, count));
+                    break;
+                }
+            }
+        }
+        let unfixable = stmts
+            .iter()
+            .zip(lay.placed.iter())
+            .filter_map(|(stmt, placed)| match &placed.bytes {
+                Ok(bytes)
+                    if !self.bad.is_clean(bytes) || (placed.gap > 0 && self.bad.contains(0)) =>
+                {
+                    Some(Unfixable {
+                        line: stmt.line,
+                        statement: stmt.code.clone(),
+                        bytes: bytes.clone(),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        let source = stmts
+            .iter()
+            .map(Statement::to_source)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let output = self.engine.asm(source.clone(), address)?;
+        Ok(Rewrite {
+            source,
+            output,
+            unfixable,
+        })
+    }
+}
+
+/// Creates the statements replacing `stmt` with the alternative `alt`.
+///
+/// The first statement keeps the label of the original one, and the last one its comment.
+fn replace(stmt: &Statement, alt: &[String]) -> Vec<Statement> {
+    let count = alt.len();
+    alt.iter()
+        .enumerate()
+        .map(|(i, code)| {
+            let label = if i == 0 { stmt.label.clone() } else { None };
+            let mut new = Statement::new(stmt.line, stmt.column, label, code.clone());
+            if i == count - 1 {
+                new.comment = stmt.comment.clone();
+            }
+            new
+        })
+        .collect()
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bad_bytes() {
+        let bad = BadBytes::from(&b"\x00\x0a\xff"[..]);
+        assert!(bad.contains(0x00));
+        assert!(bad.contains(0xff));
+        assert!(!bad.contains(0x0b));
+        assert_eq!(bad.iter().collect::<Vec<_>>(), vec![0x00, 0x0a, 0xff]);
+        assert_eq!(bad.find(&[0x90, 0x90, 0x0a]), Some(2));
+        assert!(bad.is_clean(&[0x90, 0xc3]));
+    }
+
+    #[test]
+    fn test_alternatives() {
+        let bad = BadBytes::from(&b"\x00"[..]);
+        let alts = alternatives(&bad, ffi::Mode::MODE_32, "mov eax, 0");
+        assert_eq!(alts[0], vec!["xor eax, eax"]);
+        let alts = alternatives(&bad, ffi::Mode::MODE_64, "mov rax, 0x10");
+        assert!(alts.contains(&vec![
+            "mov eax, 0x1010111".to_string(),
+            "xor eax, 0x1010101".to_string()
+        ]));
+        assert!(alts.contains(&vec!["push 0x10".to_string(), "pop rax".to_string()]));
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let rewriter = Rewriter::new(&engine, BadBytes::from(&b"\x00"[..])).unwrap();
+        let res = rewriter
+            .rewrite("mov eax, 0\nmov ebx, 0x10\nret", 0)
+            .unwrap();
+        assert!(res.unfixable.is_empty());
+        assert!(!res.output.bytes.contains(&0));
+        assert!(res.source.starts_with("xor eax, eax\n"));
+    }
+}
@@ -0,0 +1,704 @@
+//! Assembly with error recovery.
+//!
+//! [`Keystone::asm`] stops at the first statement that fails to assemble. [`CheckAssembler`]
+//! assembles every statement on its own instead, so that all the errors of a program are
+//! reported at once, along with the output of the statements that succeeded. Failed statements
+//! are left out of the output, or replaced with placeholder bytes given with
+//! [`CheckAssembler::placeholder`] to keep the following statements at their expected address.
+//!
+//! ```no_run
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let report = engine.asm_all("mov eax, 1\nmov eax, ebx, ecx\nbad\nret", 0);
+//! for diagnostic in &report.diagnostics {
+//!     println!("{}", diagnostic);
+//! }
+//! ```
+//!
+//! Diagnostics can also be rendered like compiler errors, with the source line, a caret under
+//! the statement and hints for common mistakes, through [`RichDiagnostic`]. With the `miette`
+//! feature, [`RichDiagnostic`] implements `miette::Diagnostic`. With the `serde` feature,
+//! diagnostics are serializable and can be exported as JSON or SARIF with [`to_json`] and
+//! [`to_sarif`].
+//!
+//! ```no_run
+//! # use keystone_engine::{Arch, Keystone, Mode};
+//! let engine = Keystone::new(Arch::ARM, Mode::ARM).expect("Could not initialize Keystone");
+//! let source = "mov r0, #1\nmov x0, #1";
+//! let report = engine.asm_all(source, 0);
+//! eprint!("{}", report.render(&engine, "payload.s", source));
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::layout::{self, Statement};
+use crate::{ffi, Keystone, KeystoneError};
+
+/// Error of a statement.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Diagnostic {
+    /// Line of the statement in the source, starting at 1.
+    pub line: usize,
+    /// Column of the statement in its line, starting at 1.
+    pub column: usize,
+    /// Address of the statement.
+    pub address: u64,
+    /// Code of the statement.
+    pub statement: String,
+    /// Error returned when assembling the statement.
+    pub error: KeystoneError,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} (`{}`)",
+            self.line, self.column, self.error, self.statement
+        )
+    }
+}
+
+/// Result of an assembly with error recovery.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Report {
+    /// Encoding of the statements that were assembled, and placeholders of those that failed.
+    pub bytes: Vec<u8>,
+    /// Addresses of the labels defined by the program.
+    pub labels: HashMap<String, u64>,
+    /// Errors of the statements that failed, in source order.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    /// Returns `true` if every statement was assembled.
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    /// Attaches the `source` of the program, read from `file`, to the diagnostics. `engine` is
+    /// the instance that assembled it, which is used to suggest fixes.
+    pub fn rich(&self, engine: &Keystone, file: &str, source: &str) -> Vec<RichDiagnostic> {
+        let source: Arc<str> = Arc::from(source);
+        self.diagnostics
+            .iter()
+            .map(|d| RichDiagnostic::new(d, engine, file, source.clone()))
+            .collect()
+    }
+
+    /// Renders the diagnostics as plain text (see [`RichDiagnostic::render`]).
+    pub fn render(&self, engine: &Keystone, file: &str, source: &str) -> String {
+        self.rich(engine, file, source)
+            .iter()
+            .map(RichDiagnostic::render)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Assembles programs, collecting the errors of every statement.
+#[derive(Debug)]
+pub struct CheckAssembler<'a> {
+    /// Keystone instance used to assemble the statements.
+    engine: &'a Keystone,
+    /// Address of the program.
+    address: u64,
+    /// Bytes substituted for the statements that fail.
+    placeholder: Vec<u8>,
+}
+
+impl<'a> CheckAssembler<'a> {
+    /// Creates a new assembler placing programs at `address`.
+    pub fn new(engine: &'a Keystone, address: u64) -> Self {
+        CheckAssembler {
+            engine,
+            address,
+            placeholder: vec![],
+        }
+    }
+
+    /// Replaces the statements that fail with `bytes`, e.g. a breakpoint instruction. They are
+    /// left out by default.
+    pub fn placeholder(mut self, bytes: &[u8]) -> Self {
+        self.placeholder = bytes.to_vec();
+        self
+    }
+
+    /// Assembles `source`, returning the errors of every statement that failed.
+    pub fn assemble(&self, source: &str) -> Report {
+        let mut stmts = layout::parse(self.engine.arch(), source);
+        let mut lay = layout::layout(self.engine, &stmts, self.address, |_| None);
+        let mut failed: HashMap<usize, KeystoneError> = HashMap::new();
+        for (idx, placed) in lay.placed.iter().enumerate() {
+            if let Err(e) = &placed.bytes {
+                failed.insert(idx, *e);
+            }
+        }
+        // The program is laid out again with placeholders, which moves the labels that follow
+        // them.
+        if !failed.is_empty() && !self.placeholder.is_empty() {
+            let bytes: Vec<String> = self
+                .placeholder
+                .iter()
+                .map(|b| format!("0x{:02x}", b))
+                .collect();
+            let code = format!(".byte {}", bytes.join(", "));
+            let originals = stmts.clone();
+            for &idx in failed.keys() {
+                let stmt = &stmts[idx];
+                stmts[idx] =
+                    Statement::new(stmt.line, stmt.column, stmt.label.clone(), code.clone());
+            }
+            lay = layout::layout(self.engine, &stmts, self.address, |_| None);
+            stmts = originals;
+        }
+        let mut bytes = vec![];
+        let mut diagnostics = vec![];
+        for (idx, (stmt, placed)) in stmts.iter().zip(lay.placed).enumerate() {
+            let address = placed.address;
+            let error = match (failed.get(&idx), placed.into_bytes()) {
+                (Some(&e), Ok(b)) => {
+                    bytes.extend(b);
+                    e
+                }
+                (_, Ok(b)) => {
+                    bytes.extend(b);
+                    continue;
+                }
+                (_, Err(e)) => e,
+            };
+            diagnostics.push(Diagnostic {
+                line: stmt.line,
+                column: stmt.column,
+                address,
+                statement: stmt.code.clone(),
+                error,
+            });
+        }
+        Report {
+            bytes,
+            labels: lay.labels,
+            diagnostics,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Rendering
+// -----------------------------------------------------------------------------------------------
+
+/// Diagnostic along with the source it refers to.
+///
+/// [`std::fmt::Display`] only shows the error message, [`RichDiagnostic::render`] shows the
+/// whole diagnostic.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RichDiagnostic {
+    /// Name of the file the source was read from.
+    pub file: String,
+    /// Line of the statement, starting at 1.
+    pub line: usize,
+    /// Column of the statement, starting at 1.
+    pub column: usize,
+    /// Offset of the statement in the source, in bytes.
+    pub offset: usize,
+    /// Length of the statement in bytes, or of its first line for multi-line statements.
+    pub length: usize,
+    /// Source of the program.
+    pub source: Arc<str>,
+    /// Error returned when assembling the statement.
+    pub error: KeystoneError,
+    /// Suggested fix, if any.
+    pub hint: Option<String>,
+}
+
+impl RichDiagnostic {
+    /// Creates a diagnostic from a statement error of `source`.
+    fn new(diagnostic: &Diagnostic, engine: &Keystone, file: &str, source: Arc<str>) -> Self {
+        let start = source
+            .split_inclusive('\n')
+            .take(diagnostic.line.saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>();
+        let text = source[start..].lines().next().unwrap_or("");
+        let code = diagnostic.statement.lines().next().unwrap_or("");
+        // The column of labeled statements is the one of their label.
+        let mut column = diagnostic.column.saturating_sub(1).min(text.len());
+        while !text.is_char_boundary(column) {
+            column -= 1;
+        }
+        let column = text[column..].find(code).map_or(column, |idx| column + idx);
+        RichDiagnostic {
+            file: file.to_string(),
+            line: diagnostic.line,
+            column: column + 1,
+            offset: start + column,
+            length: code.len().max(1),
+            hint: hint(diagnostic, engine.arch(), engine.mode()),
+            error: diagnostic.error,
+            source,
+        }
+    }
+
+    /// Returns the description of the error, as given by Keystone for engine errors.
+    pub fn message(&self) -> String {
+        match self.error {
+            KeystoneError::Engine(e) => e.strerror(),
+            KeystoneError::Misc(e) => e.to_string(),
+        }
+    }
+
+    /// Returns the line of the statement, along with the text before the statement and the
+    /// statement itself, or `None` if the position doesn't match the source.
+    fn snippet(&self) -> Option<(&str, &str, &str)> {
+        let column = self.column.checked_sub(1)?;
+        let start = self.offset.checked_sub(column)?;
+        let line = self.source.get(start..)?.lines().next().unwrap_or("");
+        let (before, rest) = (line.get(..column)?, line.get(column..)?);
+        let end = (0..=self.length.min(rest.len()))
+            .rev()
+            .find(|&end| rest.is_char_boundary(end))
+            .unwrap_or(0);
+        Some((line, before, &rest[..end]))
+    }
+
+    /// Renders the diagnostic as plain text, in the style of rustc.
+    ///
+    /// The source line is left out if the position of the diagnostic doesn't match its source.
+    pub fn render(&self) -> String {
+        let number = self.line.to_string();
+        let pad = " ".repeat(number.len());
+        let mut out = format!(
+            "error: {}\n{}--> {}:{}:{}\n",
+            self.message(),
+            pad,
+            self.file,
+            self.line,
+            self.column
+        );
+        if let Some((text, before, marked)) = self.snippet() {
+            // Tabs are kept so that the carets line up with the text, and other characters are
+            // assumed to be one column wide.
+            let indent: String = before
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!(
+                "{} |\n{} | {}\n{} | {}{}\n",
+                pad,
+                number,
+                text,
+                pad,
+                indent,
+                "^".repeat(marked.chars().count().max(1))
+            ));
+        }
+        if let Some(hint) = &self.hint {
+            out.push_str(&format!("{} = hint: {}\n", pad, hint));
+        }
+        out
+    }
+}
+
+impl std::error::Error for RichDiagnostic {}
+
+impl std::fmt::Display for RichDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+#[cfg(feature = "miette")]
+impl miette::Diagnostic for RichDiagnostic {
+    fn code<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        let name = match self.error {
+            KeystoneError::Engine(e) => e.name(),
+            KeystoneError::Misc(e) => e.name(),
+        };
+        Some(Box::new(format!("keystone::{}", name)))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        self.hint
+            .as_ref()
+            .map(|h| Box::new(h) as Box<dyn std::fmt::Display>)
+    }
+
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        Some(self)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        Some(Box::new(std::iter::once(
+            miette::LabeledSpan::new_primary_with_span(None, (self.offset, self.length)),
+        )))
+    }
+}
+
+// The source is named after the file, for the header of the snippets.
+#[cfg(feature = "miette")]
+impl miette::SourceCode for RichDiagnostic {
+    fn read_span<'a>(
+        &'a self,
+        span: &miette::SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> std::result::Result<Box<dyn miette::SpanContents<'a> + 'a>, miette::MietteError> {
+        let contents = self
+            .source
+            .read_span(span, context_lines_before, context_lines_after)?;
+        Ok(Box::new(miette::MietteSpanContents::new_named(
+            self.file.clone(),
+            contents.data(),
+            *contents.span(),
+            contents.line(),
+            contents.column(),
+            contents.line_count(),
+        )))
+    }
+}
+
+/// Returns the words of `code`, lowercased.
+fn words(code: &str) -> Vec<String> {
+    code.split(|c: char| !c.is_ascii_alphanumeric())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// Returns `true` if `word` is `prefix` followed by a number up to `max`.
+fn numbered(word: &str, prefix: char, max: u32) -> bool {
+    word.strip_prefix(prefix)
+        .and_then(|n| n.parse::<u32>().ok())
+        .is_some_and(|n| n <= max)
+}
+
+/// Suggests a fix for common mistakes, such as using registers of another architecture.
+fn hint(diagnostic: &Diagnostic, arch: ffi::Arch, mode: ffi::Mode) -> Option<String> {
+    const X86_64_REGISTERS: &[&str] = &[
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "rip", "r8", "r9", "r10", "r11",
+        "r12", "r13", "r14", "r15",
+    ];
+    let error = match diagnostic.error {
+        KeystoneError::Engine(e) => e,
+        KeystoneError::Misc(_) => return None,
+    };
+    let words = words(&diagnostic.statement);
+    let operand = matches!(
+        error,
+        ffi::Error::ASM_INVALIDOPERAND | ffi::Error::ASM_MNEMONICFAIL
+    );
+    match arch {
+        _ if error == ffi::Error::ASM_SYMBOL_MISSING => {
+            Some("define the symbol, or resolve it with Keystone::asm_with_resolver".to_string())
+        }
+        ffi::Arch::ARM if operand && words.iter().any(|w| numbered(w, 'x', 30)) => {
+            Some("did you mean Arch::ARM64?".to_string())
+        }
+        ffi::Arch::ARM64 if operand && words.iter().any(|w| numbered(w, 'r', 15)) => {
+            Some("did you mean Arch::ARM?".to_string())
+        }
+        ffi::Arch::X86
+            if operand
+                && mode != ffi::Mode::MODE_64
+                && words.iter().any(|w| X86_64_REGISTERS.contains(&w.as_str())) =>
+        {
+            Some("did you mean Mode::MODE_64?".to_string())
+        }
+        ffi::Arch::X86 if diagnostic.statement.contains('%') => Some(
+            "AT&T syntax requires OptionValue::SYNTAX_ATT to be set with Keystone::option"
+                .to_string(),
+        ),
+        _ => None,
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Serialization
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Diagnostic {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("Diagnostic", 5)?;
+        s.serialize_field("line", &self.line)?;
+        s.serialize_field("column", &self.column)?;
+        s.serialize_field("address", &self.address)?;
+        s.serialize_field("statement", &self.statement)?;
+        s.serialize_field("error", &self.error)?;
+        s.end()
+    }
+}
+
+// The source is left out, only the location of the statement is kept.
+#[cfg(feature = "serde")]
+impl serde::Serialize for RichDiagnostic {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("RichDiagnostic", 6)?;
+        s.serialize_field("file", &self.file)?;
+        s.serialize_field("line", &self.line)?;
+        s.serialize_field("column", &self.column)?;
+        s.serialize_field("length", &self.length)?;
+        s.serialize_field("error", &self.error)?;
+        s.serialize_field("hint", &self.hint)?;
+        s.end()
+    }
+}
+
+/// Serializes diagnostics as a JSON array.
+#[cfg(feature = "serde")]
+pub fn to_json(diagnostics: &[RichDiagnostic]) -> String {
+    serde_json::to_string_pretty(diagnostics).expect("diagnostics are always serializable")
+}
+
+/// Serializes diagnostics as a SARIF 2.1.0 log, for code scanning tools.
+///
+/// Every kind of error is a rule, identified by its name (e.g. `ASM_INVALIDOPERAND`), and the
+/// numeric code of engine errors is stored in the `code` property of rules and results. The
+/// payload of misc errors (e.g. the position of a NUL byte) is stored in the `details` property
+/// of results.
+#[cfg(feature = "serde")]
+pub fn to_sarif(diagnostics: &[RichDiagnostic]) -> String {
+    use serde_json::json;
+
+    let id = |d: &RichDiagnostic| match d.error {
+        KeystoneError::Engine(e) => (e.name(), Some(e.code()), None),
+        KeystoneError::Misc(e) => (e.name(), None, e.details()),
+    };
+    let mut rules = vec![];
+    let mut rule_ids: Vec<&str> = vec![];
+    let mut results = vec![];
+    for d in diagnostics {
+        let (name, code, details) = id(d);
+        let index = match rule_ids.iter().position(|r| *r == name) {
+            Some(index) => index,
+            None => {
+                rules.push(json!({
+                    "id": name,
+                    "shortDescription": { "text": d.message() },
+                    "properties": { "code": code },
+                }));
+                rule_ids.push(name);
+                rule_ids.len() - 1
+            }
+        };
+        results.push(json!({
+            "ruleId": name,
+            "ruleIndex": index,
+            "level": "error",
+            "message": { "text": d.message() },
+            "locations": [{
+                "physicalLocation": {
+                    "artifactLocation": { "uri": d.file },
+                    "region": {
+                        "startLine": d.line,
+                        "startColumn": d.column,
+                        "endColumn": d.column + d.length,
+                    },
+                },
+            }],
+            "properties": { "code": code, "details": details, "hint": d.hint },
+        }));
+    }
+    let log = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    });
+    serde_json::to_string_pretty(&log).expect("diagnostics are always serializable")
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi;
+
+    #[test]
+    fn test_hints() {
+        let diagnostic = |statement: &str, error| Diagnostic {
+            line: 2,
+            column: 1,
+            address: 0,
+            statement: statement.to_string(),
+            error: KeystoneError::Engine(error),
+        };
+        let arm = (ffi::Arch::ARM, ffi::Mode::ARM);
+        let d = diagnostic("mov x0, #1", ffi::Error::ASM_INVALIDOPERAND);
+        assert_eq!(
+            hint(&d, arm.0, arm.1).as_deref(),
+            Some("did you mean Arch::ARM64?")
+        );
+        let d = diagnostic("mov r0, #1", ffi::Error::ASM_INVALIDOPERAND);
+        assert_eq!(hint(&d, arm.0, arm.1), None);
+        assert_eq!(
+            hint(&d, ffi::Arch::ARM64, ffi::Mode::LITTLE_ENDIAN).as_deref(),
+            Some("did you mean Arch::ARM?")
+        );
+        let d = diagnostic("push rax", ffi::Error::ASM_INVALIDOPERAND);
+        assert_eq!(
+            hint(&d, ffi::Arch::X86, ffi::Mode::MODE_32).as_deref(),
+            Some("did you mean Mode::MODE_64?")
+        );
+        assert_eq!(hint(&d, ffi::Arch::X86, ffi::Mode::MODE_64), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let diagnostic = RichDiagnostic {
+            file: "payload.s".to_string(),
+            line: 2,
+            column: 6,
+            offset: 16,
+            length: 10,
+            source: Arc::from("mov r0, #1\nnop; mov x0, #1\n"),
+            error: KeystoneError::Misc(crate::MiscError::KsAsm),
+            hint: Some("did you mean Arch::ARM64?".to_string()),
+        };
+        assert_eq!(
+            diagnostic.render(),
+            "error: an error occured while calling ks_asm\n \
+             --> payload.s:2:6\n  \
+             |\n\
+             2 | nop; mov x0, #1\n  \
+             |      ^^^^^^^^^^\n  \
+             = hint: did you mean Arch::ARM64?\n"
+        );
+
+        let diagnostic = RichDiagnostic {
+            line: 1,
+            column: 6,
+            offset: 5,
+            length: 4,
+            source: Arc::from("\té: bad\n"),
+            hint: None,
+            ..diagnostic
+        };
+        assert_eq!(
+            diagnostic.render(),
+            "error: an error occured while calling ks_asm\n \
+             --> payload.s:1:6\n  \
+             |\n\
+             1 | \té: bad\n  \
+             | \t   ^^^\n"
+        );
+        let diagnostic = RichDiagnostic {
+            column: 10,
+            ..diagnostic
+        };
+        assert_eq!(
+            diagnostic.render(),
+            "error: an error occured while calling ks_asm\n --> payload.s:1:10\n"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let diagnostic = RichDiagnostic {
+            file: "payload.s".to_string(),
+            line: 2,
+            column: 6,
+            offset: 16,
+            length: 4,
+            source: Arc::from("mov r0, #1\nnop; bad2\n"),
+            error: KeystoneError::Misc(crate::MiscError::KsAsm),
+            hint: None,
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&to_json(std::slice::from_ref(&diagnostic))).unwrap();
+        assert_eq!(json[0]["file"], "payload.s");
+        assert_eq!(json[0]["error"]["name"], "KsAsm");
+        assert_eq!(json[0]["error"]["code"], serde_json::Value::Null);
+
+        let engine = RichDiagnostic {
+            error: KeystoneError::Engine(ffi::Error::ASM_INVALIDOPERAND),
+            ..diagnostic.clone()
+        };
+        let error = serde_json::to_value(engine.error).unwrap();
+        assert_eq!(error["code"], 512);
+        assert_eq!(error["name"], "ASM_INVALIDOPERAND");
+
+        let nul = RichDiagnostic {
+            error: KeystoneError::Misc(crate::MiscError::InteriorNul(3)),
+            ..diagnostic.clone()
+        };
+        let error = serde_json::to_value(nul.error).unwrap();
+        assert_eq!(error["name"], "InteriorNul");
+        assert_eq!(error["details"]["position"], 3);
+
+        let sarif: serde_json::Value =
+            serde_json::from_str(&to_sarif(&[diagnostic.clone(), engine, diagnostic, nul]))
+                .unwrap();
+        assert_eq!(sarif["version"], "2.1.0");
+        let run = &sarif["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), 3);
+        assert_eq!(run["results"][1]["ruleId"], "ASM_INVALIDOPERAND");
+        assert_eq!(run["results"][1]["properties"]["code"], 512);
+        assert_eq!(run["results"][2]["ruleIndex"], 0);
+        assert_eq!(run["results"][3]["ruleId"], "InteriorNul");
+        assert_eq!(run["results"][3]["properties"]["details"]["position"], 3);
+        let region = &run["results"][0]["locations"][0]["physicalLocation"]["region"];
+        assert_eq!(region["startLine"], 2);
+        assert_eq!(region["endColumn"], 10);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let source = "nop\nbad1\nnop; bad2\nend: ret";
+        let report = CheckAssembler::new(&engine, 0).assemble(source);
+        assert!(!report.is_ok());
+        assert_eq!(report.bytes, vec![0x90, 0x90, 0xc3]);
+        assert_eq!(report.labels.get("end"), Some(&2));
+        let locations: Vec<_> = report
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.statement.as_str()))
+            .collect();
+        assert_eq!(locations, vec![(2, 1, "bad1"), (3, 6, "bad2")]);
+
+        let report = CheckAssembler::new(&engine, 0)
+            .placeholder(&[0xcc])
+            .assemble(source);
+        assert_eq!(report.bytes, vec![0x90, 0xcc, 0x90, 0xcc, 0xc3]);
+        assert_eq!(report.labels.get("end"), Some(&4));
+        assert_eq!(report.diagnostics.len(), 2);
+        assert_eq!(report.diagnostics[1].address, 3);
+
+        // Diagnostics are built by hand too, with positions that may not match the source.
+        let mut diagnostic = report.diagnostics[0].clone();
+        diagnostic.line = 0;
+        diagnostic.column = 2;
+        let report = Report {
+            diagnostics: vec![diagnostic],
+            ..report
+        };
+        let rich = report.rich(&engine, "payload.s", "é bad1");
+        assert_eq!((rich[0].column, rich[0].offset), (4, 3));
+        assert!(report
+            .render(&engine, "payload.s", "é bad1")
+            .contains("payload.s:0:4"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Payload encoders with self-decoding stubs.
+//!
+//! An encoder transforms a payload with a [`Scheme`] so that it doesn't contain forbidden bytes
+//! anymore, and prepends a decoder stub, assembled by Keystone, that restores the payload in
+//! place before jumping to it. Keys are picked automatically to avoid the forbidden bytes in both
+//! the encoded payload and the stub.
+//!
+//! Stubs are available for x86 (32-bit) and x64 engines using the Intel syntax. They rely on a
+//! `jmp`/`call`/`pop` sequence to find the payload, which must therefore be writable, and they
+//! clobber `esi`/`rsi`, `ecx`/`rcx`, `al` and `dl`.
+//!
+//! ```no_run
+//! use keystone_engine::badbytes::BadBytes;
+//! use keystone_engine::encoders::{Encoder, Scheme};
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_64).expect("Could not initialize Keystone");
+//! let encoder = Encoder::new(&engine, BadBytes::from(&b"\x00"[..])).expect("Invalid engine");
+//! let encoded = encoder
+//!     .encode(Scheme::Xor, b"\x48\x31\xc0\x00\xc3")
+//!     .expect("Could not encode payload");
+//! assert!(!encoded.to_bytes().contains(&0));
+//! ```
+
+use crate::badbytes::{BadBytes, Rewriter};
+use crate::{ffi, Keystone, MiscError, Result};
+
+// -----------------------------------------------------------------------------------------------
+// Schemes
+// -----------------------------------------------------------------------------------------------
+
+/// Encoding schemes.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Scheme {
+    /// Every byte is xored with the key.
+    Xor,
+    /// The key is added to every byte.
+    Add,
+    /// Every byte is xored with the previous encoded byte, the first one with the key.
+    RollingXor,
+}
+
+impl Scheme {
+    /// Encodes `data` with `key`.
+    pub fn encode(self, key: u8, data: &[u8]) -> Vec<u8> {
+        match self {
+            Scheme::Xor => data.iter().map(|b| b ^ key).collect(),
+            Scheme::Add => data.iter().map(|b| b.wrapping_add(key)).collect(),
+            Scheme::RollingXor => data
+                .iter()
+                .scan(key, |k, b| {
+                    *k ^= b;
+                    Some(*k)
+                })
+                .collect(),
+        }
+    }
+
+    /// Decodes `data` with `key`.
+    pub fn decode(self, key: u8, data: &[u8]) -> Vec<u8> {
+        match self {
+            Scheme::Xor => data.iter().map(|b| b ^ key).collect(),
+            Scheme::Add => data.iter().map(|b| b.wrapping_sub(key)).collect(),
+            Scheme::RollingXor => data
+                .iter()
+                .scan(key, |k, &b| {
+                    let plain = b ^ *k;
+                    *k = b;
+                    Some(plain)
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the keys for which neither the key nor the encoded `data` contain bad bytes.
+    pub fn keys<'a>(self, data: &'a [u8], bad: &'a BadBytes) -> impl Iterator<Item = u8> + 'a {
+        (1..=255u8).filter(move |&key| !bad.contains(key) && bad.is_clean(&self.encode(key, data)))
+    }
+
+    /// Returns the decoding loop of the scheme.
+    ///
+    /// `esi`/`rsi` points to the current byte and `ecx`/`rcx` holds the number of bytes left.
+    fn decoder_loop(self, key: u8, ptr: &str) -> String {
+        match self {
+            Scheme::Xor => format!("xor byte ptr [{}], {:#x}", ptr, key),
+            Scheme::Add => format!("sub byte ptr [{}], {:#x}", ptr, key),
+            Scheme::RollingXor => format!(
+                "mov dl, byte ptr [{ptr}]\nxor byte ptr [{ptr}], al\nmov al, dl",
+                ptr = ptr
+            ),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Encoder
+// -----------------------------------------------------------------------------------------------
+
+/// Encoded payload along with its decoder stub.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Encoded {
+    /// Scheme used to encode the payload.
+    pub scheme: Scheme,
+    /// Key used to encode the payload.
+    pub key: u8,
+    /// Decoder stub, to be placed right before the payload.
+    pub stub: Vec<u8>,
+    /// Encoded payload.
+    pub payload: Vec<u8>,
+}
+
+impl Encoded {
+    /// Returns the stub followed by the encoded payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.stub[..], &self.payload[..]].concat()
+    }
+
+    /// Decodes the payload.
+    pub fn decode(&self) -> Vec<u8> {
+        self.scheme.decode(self.key, &self.payload)
+    }
+}
+
+/// Encodes payloads and generates their decoder stubs.
+#[derive(Debug)]
+pub struct Encoder<'a> {
+    /// Keystone instance used to assemble the stubs.
+    engine: &'a Keystone,
+    /// Forbidden bytes.
+    bad: BadBytes,
+}
+
+impl<'a> Encoder<'a> {
+    /// Creates a new encoder.
+    ///
+    /// Returns [`ffi::Error::ARCH`] if no stub is available for the architecture of `engine`, and
+    /// [`ffi::Error::MODE`] for its mode.
+    pub fn new(engine: &'a Keystone, bad: BadBytes) -> Result<Self> {
+        if engine.arch() != ffi::Arch::X86 {
+            return Err(ffi::Error::ARCH)?;
+        }
+        if !engine
+            .mode()
+            .intersects(ffi::Mode::MODE_32 | ffi::Mode::MODE_64)
+        {
+            return Err(ffi::Error::MODE)?;
+        }
+        Ok(Encoder { engine, bad })
+    }
+
+    /// Returns the source of the decoder stub for a payload of `len` bytes.
+    fn stub_source(&self, scheme: Scheme, key: u8, len: usize) -> String {
+        let ptr = if self.engine.mode().contains(ffi::Mode::MODE_64) {
+            "rsi"
+        } else {
+            "esi"
+        };
+        let init = match scheme {
+            Scheme::RollingXor => format!("mov al, {:#x}\n", key),
+            _ => String::new(),
+        };
+        format!(
+            "jmp get_payload\n\
+             decoder:\n\
+             pop {ptr}\n\
+             push {ptr}\n\
+             mov ecx, {len:#x}\n\
+             {init}\
+             decode:\n\
+             {body}\n\
+             inc {ptr}\n\
+             loop decode\n\
+             ret\n\
+             get_payload:\n\
+             call decoder",
+            ptr = ptr,
+            len = len,
+            init = init,
+            body = scheme.decoder_loop(key, ptr),
+        )
+    }
+
+    /// Encodes `payload` with `scheme` and generates the corresponding decoder stub.
+    ///
+    /// Returns [`MiscError::BadBytes`] if no key avoids the forbidden bytes.
+    pub fn encode(&self, scheme: Scheme, payload: &[u8]) -> Result<Encoded> {
+        if payload.is_empty() {
+            return Ok(Encoded {
+                scheme,
+                key: 0,
+                stub: vec![],
+                payload: vec![],
+            });
+        }
+        let rewriter = Rewriter::new(self.engine, self.bad)?;
+        for key in scheme.keys(payload, &self.bad) {
+            let source = self.stub_source(scheme, key, payload.len());
+            let stub = rewriter.rewrite(&source, 0)?.output.bytes;
+            if self.bad.is_clean(&stub) {
+                return Ok(Encoded {
+                    scheme,
+                    key,
+                    stub,
+                    payload: scheme.encode(key, payload),
+                });
+            }
+        }
+        Err(MiscError::BadBytes)?
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"\x31\xc0\x50\x68\x2f\x2f\x73\x68\x00\x0a\xff\x00";
+
+    #[test]
+    fn test_schemes() {
+        for scheme in [Scheme::Xor, Scheme::Add, Scheme::RollingXor] {
+            for key in [0x01, 0x42, 0xff] {
+                let encoded = scheme.encode(key, PAYLOAD);
+                assert_eq!(encoded.len(), PAYLOAD.len());
+                assert_eq!(scheme.decode(key, &encoded), PAYLOAD);
+            }
+        }
+        assert_eq!(
+            Scheme::RollingXor.encode(0x01, b"\x01\x02"),
+            vec![0x00, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let bad = BadBytes::from(&b"\x00\x0a"[..]);
+        for scheme in [Scheme::Xor, Scheme::Add, Scheme::RollingXor] {
+            let key = scheme.keys(PAYLOAD, &bad).next().expect("no key found");
+            let encoded = scheme.encode(key, PAYLOAD);
+            assert!(bad.is_clean(&encoded));
+            assert_eq!(scheme.decode(key, &encoded), PAYLOAD);
+        }
+    }
+
+    #[test]
+    fn test_encoder() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_64).unwrap();
+        let bad = BadBytes::from(&b"\x00\x0a"[..]);
+        let encoder = Encoder::new(&engine, bad).unwrap();
+        for scheme in [Scheme::Xor, Scheme::Add, Scheme::RollingXor] {
+            let encoded = encoder.encode(scheme, PAYLOAD).unwrap();
+            assert!(bad.is_clean(&encoded.to_bytes()));
+            assert_eq!(encoded.decode(), PAYLOAD);
+        }
+    }
+}
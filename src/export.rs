@@ -0,0 +1,412 @@
+//! Byte-array exporters.
+//!
+//! An [`Exporter`] formats machine code as a byte array or string literal of a programming
+//! language, ready to be pasted into a source file. When exporting a [`Listing`], every source
+//! line with an encoding is written on its own line and followed by a comment showing it.
+//!
+//! ```no_run
+//! use keystone_engine::export::{Exporter, Language, Style};
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let output = engine
+//!     .asm("xor eax, eax; ret".to_string(), 0)
+//!     .expect("Could not assemble");
+//! println!("{}", output.export(&Exporter::new(Language::C).name("shellcode")));
+//!
+//! let listing = engine.listing("xor eax, eax\nret", 0).expect("Could not assemble");
+//! let exporter = Exporter::new(Language::Python).style(Style::String);
+//! println!("{}", exporter.export_listing(&listing));
+//! ```
+
+use crate::listing::Listing;
+use crate::KeystoneOutput;
+
+/// Target languages.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Language {
+    /// C, as an `unsigned char` array.
+    C,
+    /// Rust, as a `u8` array constant.
+    Rust,
+    /// Python, as `bytes`.
+    Python,
+    /// Go, as a `[]byte` slice.
+    Go,
+    /// JavaScript, as an `Uint8Array`.
+    JavaScript,
+}
+
+/// Representation of the bytes.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Style {
+    /// List of integer literals, e.g. `0x90, 0xc3`.
+    Array,
+    /// String literal with escape sequences, e.g. `"\x90\xc3"`.
+    ///
+    /// Rust byte strings can't contain comments, those of listings are omitted.
+    String,
+}
+
+/// Formats machine code as source code.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Exporter {
+    /// Target language.
+    language: Language,
+    /// Representation of the bytes.
+    style: Style,
+    /// Name of the variable.
+    name: String,
+    /// Maximum width of the lines holding bytes, comments excluded.
+    line_width: usize,
+    /// Whether hexadecimal digits are uppercase.
+    uppercase: bool,
+    /// Whether printable ASCII characters are kept as-is in string literals.
+    printable: bool,
+}
+
+impl Exporter {
+    /// Creates an exporter for `language`, using the [`Style::Array`] style, a variable named
+    /// `buf` and lines of at most 80 characters.
+    pub fn new(language: Language) -> Self {
+        Exporter {
+            language,
+            style: Style::Array,
+            name: "buf".to_string(),
+            line_width: 80,
+            uppercase: false,
+            printable: false,
+        }
+    }
+
+    /// Sets the representation of the bytes.
+    pub fn style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Sets the name of the variable, which is uppercased for Rust constants.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Sets the maximum width of the lines holding bytes, comments excluded. At least one byte is
+    /// written per line.
+    pub fn line_width(mut self, width: usize) -> Self {
+        self.line_width = width;
+        self
+    }
+
+    /// Uses uppercase hexadecimal digits.
+    pub fn uppercase(mut self, uppercase: bool) -> Self {
+        self.uppercase = uppercase;
+        self
+    }
+
+    /// Keeps printable ASCII characters as-is in string literals instead of escaping them.
+    pub fn printable(mut self, printable: bool) -> Self {
+        self.printable = printable;
+        self
+    }
+
+    /// Exports `bytes`.
+    pub fn export_bytes(&self, bytes: &[u8]) -> String {
+        self.format(&[(bytes, None)], bytes.len())
+    }
+
+    /// Exports the encoding of `listing`, with each source line as a comment.
+    pub fn export_listing(&self, listing: &Listing) -> String {
+        let groups: Vec<_> = listing
+            .lines
+            .iter()
+            .filter(|l| !l.bytes.is_empty())
+            .map(|l| (&l.bytes[..], Some(l.source.trim())))
+            .collect();
+        let len = groups.iter().map(|(b, _)| b.len()).sum();
+        self.format(&groups, len)
+    }
+
+    /// Returns the representation of `byte`, given the byte that precedes it.
+    fn item(&self, byte: u8, prev: Option<u8>) -> String {
+        let hex = |b: u8| {
+            if self.uppercase {
+                format!("{:02X}", b)
+            } else {
+                format!("{:02x}", b)
+            }
+        };
+        match self.style {
+            Style::Array => format!("0x{},", hex(byte)),
+            Style::String => {
+                let printable = self.printable && (0x20..0x7f).contains(&byte);
+                // C hex escapes consume every hex digit that follows them.
+                let after_escape =
+                    prev.is_some_and(|p| !self.printable || !(0x20..0x7f).contains(&p));
+                match byte {
+                    b'"' | b'\\' if printable => format!("\\{}", byte as char),
+                    _ if printable
+                        && !(self.language == Language::C
+                            && after_escape
+                            && byte.is_ascii_hexdigit()) =>
+                    {
+                        (byte as char).to_string()
+                    }
+                    _ => format!("\\x{}", hex(byte)),
+                }
+            }
+        }
+    }
+
+    /// Splits the groups of bytes into lines, returning the content of each line along with the
+    /// comment to write after it.
+    fn lines<'a>(&self, groups: &[(&[u8], Option<&'a str>)]) -> Vec<(String, Option<&'a str>)> {
+        let (open, sep, close) = match (self.style, self.language) {
+            (Style::Array, _) => ("", " ", ""),
+            (Style::String, Language::Python) => ("b\"", "", "\""),
+            (Style::String, _) => ("\"", "", "\""),
+        };
+        let budget = self.line_width.saturating_sub(4 + open.len() + close.len());
+        let mut lines = vec![];
+        let mut prev = None;
+        for &(bytes, comment) in groups {
+            let mut line = String::new();
+            let mut count = 0;
+            for &byte in bytes {
+                let item = self.item(byte, prev);
+                prev = Some(byte);
+                let extra = if count == 0 { 0 } else { sep.len() };
+                if count > 0 && line.len() + extra + item.len() > budget {
+                    lines.push((format!("{}{}{}", open, line, close), None));
+                    line.clear();
+                    count = 0;
+                }
+                if count > 0 {
+                    line.push_str(sep);
+                }
+                line.push_str(&item);
+                count += 1;
+            }
+            if count > 0 {
+                lines.push((format!("{}{}{}", open, line, close), comment));
+            }
+        }
+        lines
+    }
+
+    /// Formats groups of bytes, `len` bytes in total.
+    fn format(&self, groups: &[(&[u8], Option<&str>)], len: usize) -> String {
+        let name = &self.name;
+        let rust_name = self.name.to_uppercase();
+        let (header, footer) = match (self.style, self.language) {
+            (Style::Array, Language::C) => (format!("unsigned char {}[{}] = {{", name, len), "};"),
+            (Style::Array, Language::Rust) => {
+                (format!("pub const {}: [u8; {}] = [", rust_name, len), "];")
+            }
+            (Style::Array, Language::Python) => (format!("{} = bytes([", name), "])"),
+            (Style::Array, Language::Go) => (format!("var {} = []byte{{", name), "}"),
+            (Style::Array, Language::JavaScript) => {
+                (format!("const {} = new Uint8Array([", name), "]);")
+            }
+            (Style::String, Language::C) => (format!("unsigned char {}[] =", name), ";"),
+            (Style::String, Language::Rust) => (
+                format!("pub const {}: &[u8; {}] = b\"\\", rust_name, len),
+                "\";",
+            ),
+            (Style::String, Language::Python) => (format!("{} = (", name), ")"),
+            (Style::String, Language::Go) => (format!("var {} = []byte(", name), ")"),
+            (Style::String, Language::JavaScript) => (
+                format!("const {} = Uint8Array.from(", name),
+                "    (c) => c.charCodeAt(0),\n);",
+            ),
+        };
+        let marker = match self.language {
+            Language::Python => "#",
+            _ => "//",
+        };
+        let mut lines = self.lines(groups);
+        // Rust byte strings are a single literal, continued with `\` on each line. Continuations
+        // skip leading whitespace, so a space starting a line is escaped.
+        if self.style == Style::String && self.language == Language::Rust {
+            lines = lines
+                .into_iter()
+                .map(|(l, _)| {
+                    let l = l.strip_prefix('"').unwrap_or(&l);
+                    let l = l.strip_suffix('"').unwrap_or(l);
+                    match l.strip_prefix(' ') {
+                        Some(rest) => (format!("\\x20{}", rest), None),
+                        None => (l.to_string(), None),
+                    }
+                })
+                .collect();
+        }
+        let count = lines.len();
+        let mut out = header;
+        out.push('\n');
+        for (idx, (line, comment)) in lines.into_iter().enumerate() {
+            let last = idx + 1 == count;
+            let suffix = match (self.style, self.language) {
+                (Style::String, Language::Go | Language::JavaScript) if last => ",",
+                (Style::String, Language::Go | Language::JavaScript) => " +",
+                (Style::String, Language::Rust) if last => footer,
+                (Style::String, Language::Rust) => "\\",
+                _ => "",
+            };
+            out.push_str("    ");
+            out.push_str(&line);
+            out.push_str(suffix);
+            if let Some(comment) = comment {
+                // A trailing backslash would continue a C comment on the next line.
+                out.push_str(&format!("  {} {}", marker, comment.trim_end_matches('\\')));
+            }
+            out.push('\n');
+        }
+        if self.style == Style::String && self.language == Language::Rust {
+            // The closing quote ends the last line, unless there is none.
+            if count == 0 {
+                out.push_str(footer);
+            } else {
+                out.pop();
+            }
+        } else if self.style == Style::String && self.language == Language::C && count > 0 {
+            // The semicolon ends the last string literal.
+            out.pop();
+            out = match out.rfind("  //") {
+                Some(idx) if out[idx..].find('\n').is_none() => {
+                    format!("{};{}", &out[..idx], &out[idx..])
+                }
+                _ => format!("{};", out),
+            };
+        } else {
+            out.push_str(footer);
+        }
+        out.push('\n');
+        out
+    }
+}
+
+impl KeystoneOutput {
+    /// Formats the encoded instructions as source code with `exporter`.
+    pub fn export(&self, exporter: &Exporter) -> String {
+        exporter.export_bytes(&self.bytes)
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::ListingLine;
+
+    const BYTES: &[u8] = &[0x31, 0xc0, 0x41, 0x42, 0xc3];
+
+    fn listing() -> Listing {
+        Listing {
+            lines: vec![
+                ListingLine {
+                    line: 1,
+                    address: Some(0),
+                    bytes: vec![0x31, 0xc0],
+                    source: "xor eax, eax".to_string(),
+                },
+                ListingLine {
+                    line: 2,
+                    address: None,
+                    bytes: vec![],
+                    source: "# nothing".to_string(),
+                },
+                ListingLine {
+                    line: 3,
+                    address: Some(2),
+                    bytes: vec![0xc3],
+                    source: "  ret".to_string(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_array() {
+        assert_eq!(
+            Exporter::new(Language::C).export_bytes(BYTES),
+            "unsigned char buf[5] = {\n    0x31, 0xc0, 0x41, 0x42, 0xc3,\n};\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::Rust)
+                .name("code")
+                .line_width(20)
+                .uppercase(true)
+                .export_bytes(BYTES),
+            "pub const CODE: [u8; 5] = [\n    0x31, 0xC0,\n    0x41, 0x42,\n    0xC3,\n];\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::Go).export_listing(&listing()),
+            "var buf = []byte{\n    0x31, 0xc0,  // xor eax, eax\n    0xc3,  // ret\n}\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::Python).export_bytes(&[0x90]),
+            "buf = bytes([\n    0x90,\n])\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::JavaScript).export_bytes(&[0x90]),
+            "const buf = new Uint8Array([\n    0x90,\n]);\n"
+        );
+    }
+
+    #[test]
+    fn test_string() {
+        let exporter = Exporter::new(Language::C).style(Style::String);
+        assert_eq!(
+            exporter.export_bytes(BYTES),
+            "unsigned char buf[] =\n    \"\\x31\\xc0\\x41\\x42\\xc3\";\n"
+        );
+        assert_eq!(
+            exporter.printable(true).export_listing(&listing()),
+            "unsigned char buf[] =\n    \"1\\xc0\"  // xor eax, eax\n    \"\\xc3\";  // ret\n"
+        );
+        // Hex digits following an escape are escaped too in C.
+        assert_eq!(
+            Exporter::new(Language::C)
+                .style(Style::String)
+                .printable(true)
+                .export_bytes(BYTES),
+            "unsigned char buf[] =\n    \"1\\xc0\\x41B\\xc3\";\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::Python)
+                .style(Style::String)
+                .export_listing(&listing()),
+            "buf = (\n    b\"\\x31\\xc0\"  # xor eax, eax\n    b\"\\xc3\"  # ret\n)\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::Rust)
+                .style(Style::String)
+                .export_listing(&listing()),
+            "pub const BUF: &[u8; 3] = b\"\\\n    \\x31\\xc0\\\n    \\xc3\";\n"
+        );
+        // Quotes are escaped, and spaces starting a continuation line too.
+        assert_eq!(
+            Exporter::new(Language::Rust)
+                .style(Style::String)
+                .printable(true)
+                .line_width(12)
+                .export_bytes(b"\"abcd e\""),
+            "pub const BUF: &[u8; 8] = b\"\\\n    \\\"abcd\\\n    \\x20e\\\"\";\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::Go)
+                .style(Style::String)
+                .export_listing(&listing()),
+            "var buf = []byte(\n    \"\\x31\\xc0\" +  // xor eax, eax\n    \"\\xc3\",  // ret\n)\n"
+        );
+        assert_eq!(
+            Exporter::new(Language::JavaScript)
+                .style(Style::String)
+                .export_bytes(&[0x90]),
+            "const buf = Uint8Array.from(\n    \"\\x90\",\n    (c) => c.charCodeAt(0),\n);\n"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Runtime loading of the Keystone library.
+//!
+//! With the `dynamic-load` feature, the crate doesn't link against Keystone at build time.
+//! Instead, the library is opened the first time it's needed, either explicitly with [`load`] or
+//! implicitly with [`load_default`], and its functions are resolved from it. The library stays
+//! loaded until the process exits.
+
+use super::Api;
+use crate::version::Version;
+
+use std::ffi::OsStr;
+use std::sync::{Mutex, OnceLock};
+
+/// Environment variable overriding the path of the library opened by [`load_default`].
+pub const LIBRARY_ENV: &str = "KEYSTONE_LIBRARY";
+
+/// Loaded library.
+static API: OnceLock<Api> = OnceLock::new();
+
+/// Serializes the loading attempts, so that the library is only opened once.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Errors that can occur while loading the Keystone library.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum LoadError {
+    /// The library could not be opened.
+    Open(String),
+    /// A function of the API is missing from the library.
+    Symbol(&'static str),
+    /// The version of the library, given as `(major, minor)`, is not supported.
+    Version(u32, u32),
+    /// A library has already been loaded.
+    AlreadyLoaded,
+}
+
+impl std::error::Error for LoadError {}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Open(e) => write!(f, "could not open the keystone library: {}", e),
+            LoadError::Symbol(s) => write!(f, "missing symbol in the keystone library: {}", s),
+            LoadError::Version(major, minor) => write!(
+                f,
+                "unsupported keystone library version {}.{} (expected {})",
+                major,
+                minor,
+                Version::supported_range()
+            ),
+            LoadError::AlreadyLoaded => write!(f, "the keystone library is already loaded"),
+        }
+    }
+}
+
+/// Returns the names tried by [`load_default`], in order.
+///
+/// Only the path given by [`LIBRARY_ENV`] is returned if the variable is set, since the user
+/// explicitly asked for that library.
+fn default_names() -> Vec<std::ffi::OsString> {
+    if let Some(path) = std::env::var_os(LIBRARY_ENV) {
+        return vec![path];
+    }
+    let mut names = vec![];
+    if cfg!(target_os = "linux") {
+        names.push("libkeystone.so.0".into());
+    }
+    names.push(libloading::library_filename("keystone"));
+    names
+}
+
+/// Opens the library at `path` and resolves the API from it, without checking whether another
+/// one is already loaded.
+fn open(path: &OsStr) -> Result<Api, LoadError> {
+    // SAFETY: initialization routines of the library are trusted, like they would be when
+    // linking against it.
+    let lib = unsafe { libloading::Library::new(path) }
+        .map_err(|e| LoadError::Open(format!("{}: {}", path.to_string_lossy(), e)))?;
+    // SAFETY: the signatures of the resolved functions are the ones of the Keystone API.
+    let api = unsafe { Api::resolve(lib)? };
+    let (mut major, mut minor) = (0, 0);
+    unsafe { (api.ks_version)(&mut major, &mut minor) };
+    if !Version::new(major, minor).is_supported() {
+        return Err(LoadError::Version(major, minor));
+    }
+    Ok(api)
+}
+
+/// Loads the Keystone library at `path`.
+///
+/// Returns [`LoadError::AlreadyLoaded`] if a library has already been loaded, including
+/// implicitly by a previous call to the API.
+pub fn load(path: impl AsRef<OsStr>) -> Result<(), LoadError> {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if API.get().is_some() {
+        return Err(LoadError::AlreadyLoaded);
+    }
+    let _ = API.set(open(path.as_ref())?);
+    Ok(())
+}
+
+/// Loads the Keystone library from its default location, unless one is already loaded.
+///
+/// If the [`LIBRARY_ENV`] environment variable is set, only the library at this path is opened,
+/// and its error is returned if it fails. Otherwise, the platform-specific names of the library
+/// are looked up in the system search paths, and the error of the first attempt is returned if
+/// they all fail.
+pub fn load_default() -> Result<(), LoadError> {
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if API.get().is_some() {
+        return Ok(());
+    }
+    let mut first_err = None;
+    for name in default_names() {
+        match open(&name) {
+            Ok(api) => {
+                let _ = API.set(api);
+                return Ok(());
+            }
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_err.expect("no library name to try"))
+}
+
+/// Returns whether the Keystone library is loaded.
+pub fn is_loaded() -> bool {
+    API.get().is_some()
+}
+
+/// Returns the loaded API, loading the library from its default location if needed.
+///
+/// The safe functions of the crate load the library before calling into it and return
+/// [`MiscError::Library`](crate::MiscError::Library) if it fails, so that this is only reached
+/// from the raw functions of [`ffi`](super).
+///
+/// # Panics
+///
+/// Panics if the library could not be loaded.
+pub(super) fn api() -> &'static Api {
+    if let Some(api) = API.get() {
+        return api;
+    }
+    if let Err(e) = load_default() {
+        panic!("{}", e);
+    }
+    API.get().expect("keystone library not loaded")
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        // `open` is used rather than `load`, which depends on whether the library was already
+        // loaded by another test.
+        assert!(matches!(
+            open(OsStr::new("/nonexistent/libkeystone.so")),
+            Err(LoadError::Open(e)) if e.starts_with("/nonexistent/libkeystone.so: ")
+        ));
+        assert_eq!(
+            LoadError::Symbol("ks_asm").to_string(),
+            "missing symbol in the keystone library: ks_asm"
+        );
+    }
+}
@@ -6,6 +6,12 @@ use libc::*;
 
 use core::marker::{PhantomData, PhantomPinned};
 
+#[cfg(feature = "dynamic-load")]
+mod dynamic;
+
+#[cfg(feature = "dynamic-load")]
+pub use dynamic::{is_loaded, load, load_default, LoadError, LIBRARY_ENV};
+
 // -----------------------------------------------------------------------------------------------
 // Types
 // -----------------------------------------------------------------------------------------------
@@ -20,6 +26,11 @@ pub struct KsEngine {
 /// Pointer to a [`KsEngine`] object.
 pub type KsHandle = std::ptr::NonNull<KsEngine>;
 
+/// Symbol resolver callback, registered with [`OptionType::SYM_RESOLVER`].
+///
+/// Returns `true` and writes the value of `symbol` into `value` if the symbol could be resolved.
+pub type SymResolver = extern "C" fn(symbol: *const c_char, value: *mut u64) -> bool;
+
 // -----------------------------------------------------------------------------------------------
 // Constants
 // -----------------------------------------------------------------------------------------------
@@ -27,6 +38,9 @@ pub type KsHandle = std::ptr::NonNull<KsEngine>;
 // These values have been generated using the const_generator.py script of the official
 // keystone repository:
 //     - https://github.com/keystone-engine/keystone/blob/0.9.2/bindings/const_generator.py
+//
+// They are checked at build time against the `keystone.h` header of the library being linked, when
+// the build script can find it (see the "Header consistency" section below).
 
 /// Keystone major API version.
 pub const API_MAJOR: c_uint = 0;
@@ -104,108 +118,167 @@ bitflags! {
     }
 }
 
-/// All type of errors encountered by Keystone API.
-#[repr(C)]
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-pub enum Error {
-    /// No error: everything was fine.
-    OK = 0,
-    /// Out-Of-Memory error: ks_open(), ks_emulate().
-    NOMEM = 1,
-    /// Unsupported architecture: ks_open().
-    ARCH = 2,
-    /// Invalid handle.
-    HANDLE = 3,
-    /// Invalid/unsupported mode: ks_open().
-    MODE = 4,
-    /// Unsupported version (bindings).
-    VERSION = 5,
-    /// Unsupported option.
-    OPT_INVALID = 6,
-    /// Unknown token in expression.
-    ASM_EXPR_TOKEN = 128,
-    /// Literal value out of range for directive.
-    ASM_DIRECTIVE_VALUE_RANGE = 129,
-    /// Expected identifier in directive.
-    ASM_DIRECTIVE_ID = 130,
-    /// Unexpected token in directive.
-    ASM_DIRECTIVE_TOKEN = 131,
-    /// Expected string in directive.
-    ASM_DIRECTIVE_STR = 132,
-    /// Expected comma in directive.
-    ASM_DIRECTIVE_COMMA = 133,
-    /// Expected relocation name in directive.
-    ASM_DIRECTIVE_RELOC_NAME = 134,
-    /// Unexpected token in .reloc directive.
-    ASM_DIRECTIVE_RELOC_TOKEN = 135,
-    /// Invalid floating point in directive.
-    ASM_DIRECTIVE_FPOINT = 136,
-    /// Unknown directive.
-    ASM_DIRECTIVE_UNKNOWN = 137,
-    /// Invalid equal directive.
-    ASM_DIRECTIVE_EQU = 138,
-    /// (Generic) invalid directive.
-    ASM_DIRECTIVE_INVALID = 139,
-    /// Invalid variant.
-    ASM_VARIANT_INVALID = 140,
-    /// Brackets expression not supported on this target.
-    ASM_EXPR_BRACKET = 141,
-    /// Unexpected symbol modifier following '@'.
-    ASM_SYMBOL_MODIFIER = 142,
-    /// Invalid symbol redefinition.
-    ASM_SYMBOL_REDEFINED = 143,
-    /// Cannot find a symbol.
-    ASM_SYMBOL_MISSING = 144,
-    /// Expected ')' in parentheses expression.
-    ASM_RPAREN = 145,
-    /// Unexpected token at start of statement.
-    ASM_STAT_TOKEN = 146,
-    /// Unsupported token yet.
-    ASM_UNSUPPORTED = 147,
-    /// Unexpected token in macro instantiation.
-    ASM_MACRO_TOKEN = 148,
-    /// Unbalanced parentheses in macro argument.
-    ASM_MACRO_PAREN = 149,
-    /// Expected '=' after formal parameter identifier.
-    ASM_MACRO_EQU = 150,
-    /// Too many positional arguments.
-    ASM_MACRO_ARGS = 151,
-    /// Macros cannot be nested more than 20 levels deep.
-    ASM_MACRO_LEVELS_EXCEED = 152,
-    /// Invalid macro string.
-    ASM_MACRO_STR = 153,
-    /// Invalid macro (generic error).
-    ASM_MACRO_INVALID = 154,
-    /// Unexpected backslash at end of escaped string.
-    ASM_ESC_BACKSLASH = 155,
-    /// Invalid octal escape sequence  (out of range).
-    ASM_ESC_OCTAL = 156,
-    /// Invalid escape sequence (unrecognized character).
-    ASM_ESC_SEQUENCE = 157,
-    /// Broken escape string.
-    ASM_ESC_STR = 158,
-    /// Invalid token.
-    ASM_TOKEN_INVALID = 159,
-    /// This instruction is unsupported in this mode.
-    ASM_INSN_UNSUPPORTED = 160,
-    /// Invalid fixup.
-    ASM_FIXUP_INVALID = 161,
-    /// Invalid label.
-    ASM_LABEL_INVALID = 162,
-    /// Invalid fragment.
-    ASM_FRAGMENT_INVALID = 163,
-    /// Generic input assembly errors (invalid operand) - architecture specific.
-    ASM_INVALIDOPERAND = 512,
-    /// Generic input assembly errors (missing feature) - architecture specific.
-    ASM_MISSINGFEATURE = 513,
-    /// Generic input assembly errors (mnemonic fail) - architecture specific.
-    ASM_MNEMONICFAIL = 514,
+/// Defines the [`Error`] enum from the error codes of Keystone, along with the conversions from
+/// and to these codes.
+///
+/// Codes are received from the library as raw integers, since any value not declared in the enum
+/// would be undefined behavior. Those are converted to [`Error::Unknown`] instead.
+macro_rules! error_codes {
+    (
+        $(#[$attr:meta])*
+        pub enum Error {
+            $($(#[$vattr:meta])* $name:ident = $code:literal,)*
+        }
+    ) => {
+        $(#[$attr])*
+        pub enum Error {
+            $($(#[$vattr])* $name,)*
+            /// Error code unknown to the bindings, e.g. from a newer version of Keystone.
+            Unknown(u32),
+        }
+
+        impl Error {
+            /// Returns the error matching the code returned by the library.
+            pub const fn from_code(code: u32) -> Self {
+                match code {
+                    $($code => Error::$name,)*
+                    _ => Error::Unknown(code),
+                }
+            }
+
+            /// Returns the code of the error, as used by the library.
+            ///
+            /// Codes are part of the ABI of Keystone and are stable across versions.
+            pub const fn code(self) -> u32 {
+                match self {
+                    $(Error::$name => $code,)*
+                    Error::Unknown(code) => code,
+                }
+            }
+
+            /// Returns the name of the error, e.g. `ASM_INVALIDOPERAND`, or `UNKNOWN` for
+            /// [`Error::Unknown`].
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(Error::$name => stringify!($name),)*
+                    Error::Unknown(_) => "UNKNOWN",
+                }
+            }
+
+            /// Returns the error named `name`, if any.
+            fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $(stringify!($name) => Some(Error::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    /// All type of errors encountered by Keystone API.
+    #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+    pub enum Error {
+        /// No error: everything was fine.
+        OK = 0,
+        /// Out-Of-Memory error: ks_open(), ks_emulate().
+        NOMEM = 1,
+        /// Unsupported architecture: ks_open().
+        ARCH = 2,
+        /// Invalid handle.
+        HANDLE = 3,
+        /// Invalid/unsupported mode: ks_open().
+        MODE = 4,
+        /// Unsupported version (bindings).
+        VERSION = 5,
+        /// Unsupported option.
+        OPT_INVALID = 6,
+        /// Unknown token in expression.
+        ASM_EXPR_TOKEN = 128,
+        /// Literal value out of range for directive.
+        ASM_DIRECTIVE_VALUE_RANGE = 129,
+        /// Expected identifier in directive.
+        ASM_DIRECTIVE_ID = 130,
+        /// Unexpected token in directive.
+        ASM_DIRECTIVE_TOKEN = 131,
+        /// Expected string in directive.
+        ASM_DIRECTIVE_STR = 132,
+        /// Expected comma in directive.
+        ASM_DIRECTIVE_COMMA = 133,
+        /// Expected relocation name in directive.
+        ASM_DIRECTIVE_RELOC_NAME = 134,
+        /// Unexpected token in .reloc directive.
+        ASM_DIRECTIVE_RELOC_TOKEN = 135,
+        /// Invalid floating point in directive.
+        ASM_DIRECTIVE_FPOINT = 136,
+        /// Unknown directive.
+        ASM_DIRECTIVE_UNKNOWN = 137,
+        /// Invalid equal directive.
+        ASM_DIRECTIVE_EQU = 138,
+        /// (Generic) invalid directive.
+        ASM_DIRECTIVE_INVALID = 139,
+        /// Invalid variant.
+        ASM_VARIANT_INVALID = 140,
+        /// Brackets expression not supported on this target.
+        ASM_EXPR_BRACKET = 141,
+        /// Unexpected symbol modifier following '@'.
+        ASM_SYMBOL_MODIFIER = 142,
+        /// Invalid symbol redefinition.
+        ASM_SYMBOL_REDEFINED = 143,
+        /// Cannot find a symbol.
+        ASM_SYMBOL_MISSING = 144,
+        /// Expected ')' in parentheses expression.
+        ASM_RPAREN = 145,
+        /// Unexpected token at start of statement.
+        ASM_STAT_TOKEN = 146,
+        /// Unsupported token yet.
+        ASM_UNSUPPORTED = 147,
+        /// Unexpected token in macro instantiation.
+        ASM_MACRO_TOKEN = 148,
+        /// Unbalanced parentheses in macro argument.
+        ASM_MACRO_PAREN = 149,
+        /// Expected '=' after formal parameter identifier.
+        ASM_MACRO_EQU = 150,
+        /// Too many positional arguments.
+        ASM_MACRO_ARGS = 151,
+        /// Macros cannot be nested more than 20 levels deep.
+        ASM_MACRO_LEVELS_EXCEED = 152,
+        /// Invalid macro string.
+        ASM_MACRO_STR = 153,
+        /// Invalid macro (generic error).
+        ASM_MACRO_INVALID = 154,
+        /// Unexpected backslash at end of escaped string.
+        ASM_ESC_BACKSLASH = 155,
+        /// Invalid octal escape sequence  (out of range).
+        ASM_ESC_OCTAL = 156,
+        /// Invalid escape sequence (unrecognized character).
+        ASM_ESC_SEQUENCE = 157,
+        /// Broken escape string.
+        ASM_ESC_STR = 158,
+        /// Invalid token.
+        ASM_TOKEN_INVALID = 159,
+        /// This instruction is unsupported in this mode.
+        ASM_INSN_UNSUPPORTED = 160,
+        /// Invalid fixup.
+        ASM_FIXUP_INVALID = 161,
+        /// Invalid label.
+        ASM_LABEL_INVALID = 162,
+        /// Invalid fragment.
+        ASM_FRAGMENT_INVALID = 163,
+        /// Generic input assembly errors (invalid operand) - architecture specific.
+        ASM_INVALIDOPERAND = 512,
+        /// Generic input assembly errors (missing feature) - architecture specific.
+        ASM_MISSINGFEATURE = 513,
+        /// Generic input assembly errors (mnemonic fail) - architecture specific.
+        ASM_MNEMONICFAIL = 514,
+    }
 }
 
 impl Error {
     /// Returns the latest error recorded error, if any.
     pub fn new(ks: KsHandle) -> Option<Self> {
-        let err = unsafe { ks_errno(ks) };
+        let err = Error::from_code(unsafe { ks_errno(ks) });
         if err == Error::OK {
             None
         } else {
@@ -214,15 +287,162 @@ impl Error {
     }
 
     /// Returns a description for a given Keystone error.
+    ///
+    /// With the `dynamic-load` feature, the name of the error is returned if the library could
+    /// not be loaded.
     pub fn strerror(self) -> String {
+        #[cfg(feature = "dynamic-load")]
+        if load_default().is_err() {
+            return format!("{:?}", self);
+        }
         unsafe {
-            std::ffi::CStr::from_ptr(ks_strerror(self))
+            std::ffi::CStr::from_ptr(ks_strerror(self.code()))
                 .to_string_lossy()
                 .into_owned()
         }
     }
 }
 
+/// Categories of [`Error`]s, following the ranges of their codes.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ErrorKind {
+    /// Errors of the engine itself, e.g. unsupported architecture or mode (codes below 128).
+    Engine,
+    /// Invalid expressions or parentheses.
+    Expression,
+    /// Invalid directives.
+    Directive,
+    /// Undefined, redefined or invalid symbols and labels.
+    Symbol,
+    /// Invalid macros or macro calls.
+    Macro,
+    /// Invalid escape sequences in strings.
+    Escape,
+    /// Other parser errors, such as unexpected tokens.
+    Parser,
+    /// Invalid operands, mnemonics or instructions, reported by the architecture-specific
+    /// backends (codes from 512).
+    Operand,
+}
+
+impl core::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ErrorKind::Engine => write!(f, "engine error"),
+            ErrorKind::Expression => write!(f, "expression error"),
+            ErrorKind::Directive => write!(f, "directive error"),
+            ErrorKind::Symbol => write!(f, "symbol error"),
+            ErrorKind::Macro => write!(f, "macro error"),
+            ErrorKind::Escape => write!(f, "escape sequence error"),
+            ErrorKind::Parser => write!(f, "parser error"),
+            ErrorKind::Operand => write!(f, "operand error"),
+        }
+    }
+}
+
+impl Error {
+    /// Returns the category of the error. Unknown errors are classified by the range of their
+    /// code.
+    pub const fn kind(self) -> ErrorKind {
+        match self {
+            Error::ASM_EXPR_TOKEN | Error::ASM_EXPR_BRACKET | Error::ASM_RPAREN => {
+                ErrorKind::Expression
+            }
+            Error::ASM_DIRECTIVE_VALUE_RANGE
+            | Error::ASM_DIRECTIVE_ID
+            | Error::ASM_DIRECTIVE_TOKEN
+            | Error::ASM_DIRECTIVE_STR
+            | Error::ASM_DIRECTIVE_COMMA
+            | Error::ASM_DIRECTIVE_RELOC_NAME
+            | Error::ASM_DIRECTIVE_RELOC_TOKEN
+            | Error::ASM_DIRECTIVE_FPOINT
+            | Error::ASM_DIRECTIVE_UNKNOWN
+            | Error::ASM_DIRECTIVE_EQU
+            | Error::ASM_DIRECTIVE_INVALID => ErrorKind::Directive,
+            Error::ASM_VARIANT_INVALID
+            | Error::ASM_SYMBOL_MODIFIER
+            | Error::ASM_SYMBOL_REDEFINED
+            | Error::ASM_SYMBOL_MISSING
+            | Error::ASM_LABEL_INVALID => ErrorKind::Symbol,
+            Error::ASM_MACRO_TOKEN
+            | Error::ASM_MACRO_PAREN
+            | Error::ASM_MACRO_EQU
+            | Error::ASM_MACRO_ARGS
+            | Error::ASM_MACRO_LEVELS_EXCEED
+            | Error::ASM_MACRO_STR
+            | Error::ASM_MACRO_INVALID => ErrorKind::Macro,
+            Error::ASM_ESC_BACKSLASH
+            | Error::ASM_ESC_OCTAL
+            | Error::ASM_ESC_SEQUENCE
+            | Error::ASM_ESC_STR => ErrorKind::Escape,
+            _ => match self.code() {
+                0..=127 => ErrorKind::Engine,
+                128..=511 => ErrorKind::Parser,
+                _ => ErrorKind::Operand,
+            },
+        }
+    }
+
+    /// Returns `true` for errors of the engine itself, see [`ErrorKind::Engine`].
+    pub const fn is_engine(self) -> bool {
+        matches!(self.kind(), ErrorKind::Engine)
+    }
+
+    /// Returns `true` for errors raised while parsing the input, i.e. all those that aren't
+    /// engine or operand errors.
+    pub const fn is_parser(self) -> bool {
+        !matches!(self.kind(), ErrorKind::Engine | ErrorKind::Operand)
+    }
+
+    /// Returns `true` for directive errors, see [`ErrorKind::Directive`].
+    pub const fn is_directive(self) -> bool {
+        matches!(self.kind(), ErrorKind::Directive)
+    }
+
+    /// Returns `true` for macro errors, see [`ErrorKind::Macro`].
+    pub const fn is_macro(self) -> bool {
+        matches!(self.kind(), ErrorKind::Macro)
+    }
+
+    /// Returns `true` for symbol errors, see [`ErrorKind::Symbol`].
+    pub const fn is_symbol(self) -> bool {
+        matches!(self.kind(), ErrorKind::Symbol)
+    }
+
+    /// Returns `true` for operand errors, see [`ErrorKind::Operand`].
+    pub const fn is_operand(self) -> bool {
+        matches!(self.kind(), ErrorKind::Operand)
+    }
+}
+
+/// Error returned when parsing an [`Error`] from an unknown name.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct ParseErrorNameError;
+
+impl std::error::Error for ParseErrorNameError {}
+
+impl core::fmt::Display for ParseErrorNameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "unknown keystone error name")
+    }
+}
+
+/// Parses an error from its name, e.g. `ASM_INVALIDOPERAND` or `KS_ERR_ASM_INVALIDOPERAND`, or
+/// from its numeric code.
+impl std::str::FromStr for Error {
+    type Err = ParseErrorNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(code) = s.parse::<u32>() {
+            return Ok(Error::from_code(code));
+        }
+        let name = s.to_ascii_uppercase();
+        let name = name.strip_prefix("KS_ERR_").unwrap_or(&name);
+        Error::from_name(name).ok_or(ParseErrorNameError)
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl core::fmt::Display for Error {
@@ -260,11 +480,144 @@ bitflags! {
     }
 }
 
+// -----------------------------------------------------------------------------------------------
+// Header consistency
+// -----------------------------------------------------------------------------------------------
+
+// Constants of the `keystone.h` header found by the build script, or none if it wasn't found.
+include!(concat!(env!("OUT_DIR"), "/keystone_h.rs"));
+
+/// Returns the value of the constant `name` in `keystone.h`, if it's defined.
+const fn header_const(name: &str) -> Option<i64> {
+    let mut i = 0;
+    while i < HEADER_CONSTS.len() {
+        let (other, value) = HEADER_CONSTS[i];
+        let (a, b) = (name.as_bytes(), other.as_bytes());
+        if a.len() == b.len() {
+            let mut j = 0;
+            while j < a.len() && a[j] == b[j] {
+                j += 1;
+            }
+            if j == a.len() {
+                return Some(value);
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Checks at compile time that the values above match the ones of `keystone.h`.
+///
+/// Constants missing from the header are ignored, so that older headers are still accepted.
+macro_rules! check_header {
+    ($($ty:ident($prefix:literal) { $($name:ident $(= $cname:literal)?),* $(,)? })*) => {
+        $($(
+            const _: () = {
+                let cname = check_header!(@cname $prefix, $name $(, $cname)?);
+                if let Some(value) = header_const(cname) {
+                    if value != check_header!(@value $ty::$name) {
+                        panic!(concat!(
+                            stringify!($ty), "::", stringify!($name),
+                            " doesn't match keystone.h"
+                        ));
+                    }
+                }
+            };
+        )*)*
+    };
+    (@cname $prefix:literal, $name:ident) => { concat!($prefix, stringify!($name)) };
+    (@cname $prefix:literal, $name:ident, $cname:literal) => { $cname };
+    (@value Mode::$name:ident) => { Mode::$name.bits() as i64 };
+    (@value OptionValue::$name:ident) => { OptionValue::$name.bits() as i64 };
+    (@value Error::$name:ident) => { Error::$name.code() as i64 };
+    (@value $ty:ident::$name:ident) => { $ty::$name as i64 };
+}
+
+check_header! {
+    Arch("KS_ARCH_") { ARM, ARM64, MIPS, X86, PPC, SPARC, SYSTEMZ, HEXAGON, EVM, MAX }
+    Mode("KS_MODE_") {
+        LITTLE_ENDIAN, BIG_ENDIAN, ARM, THUMB, V8, MICRO, MIPS3, MIPS32R6, MIPS32, MIPS64,
+        MODE_16 = "KS_MODE_16", MODE_32 = "KS_MODE_32", MODE_64 = "KS_MODE_64",
+        PPC32, PPC64, QPX, SPARC32, SPARC64, V9,
+    }
+    Error("KS_ERR_") {
+        OK, NOMEM, ARCH, HANDLE, MODE, VERSION, OPT_INVALID, ASM_EXPR_TOKEN,
+        ASM_DIRECTIVE_VALUE_RANGE, ASM_DIRECTIVE_ID, ASM_DIRECTIVE_TOKEN, ASM_DIRECTIVE_STR,
+        ASM_DIRECTIVE_COMMA, ASM_DIRECTIVE_RELOC_NAME, ASM_DIRECTIVE_RELOC_TOKEN,
+        ASM_DIRECTIVE_FPOINT, ASM_DIRECTIVE_UNKNOWN, ASM_DIRECTIVE_EQU, ASM_DIRECTIVE_INVALID,
+        ASM_VARIANT_INVALID, ASM_EXPR_BRACKET, ASM_SYMBOL_MODIFIER, ASM_SYMBOL_REDEFINED,
+        ASM_SYMBOL_MISSING, ASM_RPAREN, ASM_STAT_TOKEN, ASM_UNSUPPORTED, ASM_MACRO_TOKEN,
+        ASM_MACRO_PAREN, ASM_MACRO_EQU, ASM_MACRO_ARGS, ASM_MACRO_LEVELS_EXCEED, ASM_MACRO_STR,
+        ASM_MACRO_INVALID, ASM_ESC_BACKSLASH, ASM_ESC_OCTAL, ASM_ESC_SEQUENCE, ASM_ESC_STR,
+        ASM_TOKEN_INVALID, ASM_INSN_UNSUPPORTED, ASM_FIXUP_INVALID, ASM_LABEL_INVALID,
+        ASM_FRAGMENT_INVALID, ASM_INVALIDOPERAND, ASM_MISSINGFEATURE, ASM_MNEMONICFAIL,
+    }
+    OptionType("KS_OPT_") { SYNTAX, SYM_RESOLVER }
+    OptionValue("KS_OPT_") {
+        SYNTAX_INTEL, SYNTAX_ATT, SYNTAX_NASM, SYNTAX_MASM, SYNTAX_GAS, SYNTAX_RADIX16,
+    }
+}
+
 // -----------------------------------------------------------------------------------------------
 // API
 // -----------------------------------------------------------------------------------------------
 
-extern "C" {
+/// Declares the functions of the Keystone API.
+///
+/// By default, the functions are linked against the library at build time. With the
+/// `dynamic-load` feature, functions with the same signatures are generated instead, and forward
+/// calls to the library loaded at runtime (see [`load`]).
+macro_rules! keystone_api {
+    ($(
+        $(#[$attr:meta])*
+        pub fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;
+    )*) => {
+        #[cfg(not(feature = "dynamic-load"))]
+        extern "C" {
+            $(
+                $(#[$attr])*
+                pub fn $name($($arg: $ty),*) $(-> $ret)?;
+            )*
+        }
+
+        /// Functions resolved from a dynamically loaded Keystone library.
+        #[cfg(feature = "dynamic-load")]
+        struct Api {
+            $($name: unsafe extern "C" fn($($ty),*) $(-> $ret)?,)*
+            /// Handle to the library, which must outlive the functions above.
+            _lib: libloading::Library,
+        }
+
+        #[cfg(feature = "dynamic-load")]
+        impl Api {
+            /// Resolves the functions of the API from `lib`.
+            unsafe fn resolve(lib: libloading::Library) -> std::result::Result<Self, LoadError> {
+                Ok(Api {
+                    $($name: *lib
+                        .get(concat!(stringify!($name), "\0").as_bytes())
+                        .map_err(|_| LoadError::Symbol(stringify!($name)))?,)*
+                    _lib: lib,
+                })
+            }
+        }
+
+        $(
+            #[cfg(feature = "dynamic-load")]
+            $(#[$attr])*
+            ///
+            /// # Safety
+            ///
+            /// The requirements are the same as for the C function. The library is loaded with
+            /// [`load_default`] if it hasn't been loaded yet, and this function panics if it fails.
+            pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                (dynamic::api().$name)($($arg),*)
+            }
+        )*
+    };
+}
+
+keystone_api! {
     /// Returns the combined API version, as well as the major and minor version numbers.
     ///
     /// **Inputs:**
@@ -299,9 +652,9 @@ extern "C" {
     ///
     /// **Return value:**
     ///
-    ///  * [`Error::OK`] on success, or another value on failure (refer to [`Error`] for more
-    ///    details).
-    pub fn ks_open(arch: Arch, mode: Mode, ks: *mut Option<KsHandle>) -> Error;
+    ///  * The code of [`Error::OK`] on success, or another code on failure (refer to
+    ///    [`Error::from_code`] for more details).
+    pub fn ks_open(arch: Arch, mode: Mode, ks: *mut Option<KsHandle>) -> c_uint;
 
     /// Closes the Keystone instance.
     ///
@@ -315,8 +668,8 @@ extern "C" {
     ///
     /// **Return value:**
     ///
-    ///  * [`Error::OK`] on success, or another value on failure (refer to [`Error`] for more
-    ///    details).
+    ///  * The code of [`Error::OK`] on success, or another code on failure (refer to
+    ///    [`Error::from_code`] for more details).
     pub fn ks_close(ks: KsHandle);
 
     /// Reports the latest error number after an API call failed.
@@ -329,19 +682,19 @@ extern "C" {
     ///
     /// **Return value:**
     ///
-    ///  * The latest error code number (refer to [`Error`] for more details).
-    pub fn ks_errno(ks: KsHandle) -> Error;
+    ///  * The latest error code number (refer to [`Error::from_code`] for more details).
+    pub fn ks_errno(ks: KsHandle) -> c_uint;
 
     /// Returns a string describing the given error code.
     ///
     /// **Input:**
     ///
-    ///  * `code`: error code number (refer to [`Error`] for more details).
+    ///  * `code`: error code number (refer to [`Error::code`] for more details).
     ///
     /// **Return value:**
     ///
     ///  * A pointer to a string that describes the error code.
-    pub fn ks_strerror(code: Error) -> *const c_char;
+    pub fn ks_strerror(code: c_uint) -> *const c_char;
 
     /// Sets an option of the Keystone engine after the instance has been created.
     ///
@@ -355,7 +708,7 @@ extern "C" {
     /// **Return value:**
     ///
     ///  * A pointer to a string that describes the error code.
-    pub fn ks_option(engine: KsHandle, opt_type: OptionType, value: OptionValue) -> Error;
+    pub fn ks_option(engine: KsHandle, opt_type: OptionType, value: OptionValue) -> c_uint;
 
     /// Assembles a program from an input string containing assembly instructions.
     ///
@@ -415,15 +768,22 @@ mod tests {
 
     #[test]
     fn test_ks_arch_supported() {
-        assert!(unsafe { ks_arch_supported(Arch::ARM) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::ARM64) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::MIPS) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::X86) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::PPC) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::SPARC) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::SYSTEMZ) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::HEXAGON) != 0 });
-        assert!(unsafe { ks_arch_supported(Arch::EVM) != 0 });
+        // Only the architectures selected through the `arch-*` features are compiled in.
+        let built = |selected: bool| cfg!(keystone_all_archs) || selected;
+        let archs = [
+            (Arch::ARM, built(cfg!(feature = "arch-arm"))),
+            (Arch::ARM64, built(cfg!(feature = "arch-arm64"))),
+            (Arch::MIPS, built(cfg!(feature = "arch-mips"))),
+            (Arch::X86, built(cfg!(feature = "arch-x86"))),
+            (Arch::PPC, built(cfg!(feature = "arch-ppc"))),
+            (Arch::SPARC, built(cfg!(feature = "arch-sparc"))),
+            (Arch::SYSTEMZ, built(cfg!(feature = "arch-systemz"))),
+            (Arch::HEXAGON, built(cfg!(feature = "arch-hexagon"))),
+            (Arch::EVM, built(cfg!(feature = "arch-evm"))),
+        ];
+        for (arch, supported) in archs {
+            assert_eq!(unsafe { ks_arch_supported(arch) != 0 }, supported);
+        }
     }
 
     #[test]
@@ -431,14 +791,14 @@ mod tests {
         // ARM - valid arch/mode combination
         let mut ks = None;
         let err = unsafe { ks_open(Arch::ARM, Mode::LITTLE_ENDIAN | Mode::ARM, &mut ks) };
-        assert_eq!(err, Error::OK);
+        assert_eq!(Error::from_code(err), Error::OK);
         assert!(ks.is_some());
         unsafe { ks_close(ks.unwrap()) };
 
         // ARM64 - invalid arch/mode combination
         let mut ks = None;
         let err = unsafe { ks_open(Arch::ARM64, Mode::LITTLE_ENDIAN | Mode::ARM, &mut ks) };
-        assert_eq!(err, Error::MODE);
+        assert_eq!(Error::from_code(err), Error::MODE);
         assert!(ks.is_none());
     }
 
@@ -447,7 +807,7 @@ mod tests {
         // Create a handle to the Keystone engine.
         let mut ks = None;
         let err = unsafe { ks_open(Arch::ARM, Mode::LITTLE_ENDIAN | Mode::ARM, &mut ks) };
-        assert_eq!(err, Error::OK);
+        assert_eq!(Error::from_code(err), Error::OK);
         assert!(ks.is_some());
 
         // Assemble instructions.
@@ -490,7 +850,7 @@ mod tests {
         // Create a handle to the Keystone engine.
         let mut ks = None;
         let err = unsafe { ks_open(Arch::ARM, Mode::LITTLE_ENDIAN | Mode::ARM, &mut ks) };
-        assert_eq!(err, Error::OK);
+        assert_eq!(Error::from_code(err), Error::OK);
         assert!(ks.is_some());
 
         // Assemble instructions.
@@ -514,7 +874,7 @@ mod tests {
         };
         assert_eq!(err, -1);
         let errno = unsafe { ks_errno(ks.unwrap()) };
-        assert_eq!(errno, Error::ASM_INVALIDOPERAND);
+        assert_eq!(Error::from_code(errno), Error::ASM_INVALIDOPERAND);
         let err_str = unsafe {
             std::ffi::CStr::from_ptr(ks_strerror(errno))
                 .to_string_lossy()
@@ -528,11 +888,46 @@ mod tests {
         // Create a handle to the Keystone engine.
         let mut ks = None;
         let err = unsafe { ks_open(Arch::X86, Mode::MODE_32, &mut ks) };
-        assert_eq!(err, Error::OK);
+        assert_eq!(Error::from_code(err), Error::OK);
         assert!(ks.is_some());
 
         // Change an option after the instance has been created.
         let err = unsafe { ks_option(ks.unwrap(), OptionType::SYNTAX, OptionValue::SYNTAX_ATT) };
-        assert_eq!(err, Error::OK);
+        assert_eq!(Error::from_code(err), Error::OK);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(Error::from_code(129), Error::ASM_DIRECTIVE_VALUE_RANGE);
+        assert_eq!(Error::ASM_MNEMONICFAIL.code(), 514);
+        assert_eq!(Error::from_code(1000), Error::Unknown(1000));
+        assert_eq!(Error::Unknown(1000).code(), 1000);
+        assert_eq!(Error::ASM_SYMBOL_MISSING.name(), "ASM_SYMBOL_MISSING");
+        assert_eq!(Error::Unknown(1000).name(), "UNKNOWN");
+        assert!(!Error::Unknown(1000).strerror().is_empty());
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        assert!(Error::NOMEM.is_engine());
+        assert!(Error::ASM_DIRECTIVE_EQU.is_directive());
+        assert!(Error::ASM_MACRO_ARGS.is_macro());
+        assert!(Error::ASM_SYMBOL_MISSING.is_symbol());
+        assert!(Error::ASM_INVALIDOPERAND.is_operand());
+        assert!(Error::ASM_STAT_TOKEN.is_parser());
+        assert!(!Error::ASM_MNEMONICFAIL.is_parser());
+        assert!(Error::ASM_INSN_UNSUPPORTED.is_parser());
+        assert_eq!(Error::ASM_ESC_OCTAL.kind(), ErrorKind::Escape);
+        assert_eq!(Error::Unknown(170).kind(), ErrorKind::Parser);
+        assert_eq!(Error::Unknown(600).kind(), ErrorKind::Operand);
+
+        for err in (0..600).map(Error::from_code) {
+            if err != Error::Unknown(err.code()) {
+                assert_eq!(err.name().parse(), Ok(err));
+            }
+            assert_eq!(err.code().to_string().parse(), Ok(err));
+        }
+        assert_eq!("ks_err_asm_rparen".parse::<Error>(), Ok(Error::ASM_RPAREN));
+        assert_eq!("INVALID".parse::<Error>(), Err(ParseErrorNameError));
     }
 }
@@ -0,0 +1,291 @@
+//! Sparse memory images.
+//!
+//! Programs using `.org` to jump between addresses, e.g. firmware patches, are returned by
+//! [`Keystone::asm`] as a single buffer in which the jumps are zero-filled. [`SparseImage`] keeps
+//! the assembled content as extents instead, and records the gaps left by `.org` separately, so
+//! that they can be told apart from actual zeros.
+//!
+//! ```no_run
+//! use keystone_engine::image::SparseImage;
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::ARM, Mode::ARM).expect("Could not initialize Keystone");
+//! let image = SparseImage::assemble(&engine, "nop\n.org 0x100\nbx lr", 0x8000)
+//!     .expect("Could not assemble");
+//! let mut firmware = std::fs::read("firmware.bin").expect("Could not read firmware");
+//! image.merge_onto(&mut firmware, 0x8000).expect("Patch out of range");
+//! println!("{}", image.to_ihex().expect("Address out of range"));
+//! ```
+
+use std::path::Path;
+
+use crate::layout;
+use crate::{Keystone, MiscError, Result};
+
+/// Number of data bytes per Intel HEX record.
+const IHEX_RECORD_SIZE: usize = 16;
+
+/// Contiguous assembled content.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Extent {
+    /// Address of the first byte.
+    pub address: u64,
+    /// Content of the extent.
+    pub bytes: Vec<u8>,
+}
+
+impl Extent {
+    /// Returns the address following the end of the extent.
+    pub fn end(&self) -> u64 {
+        self.address + self.bytes.len() as u64
+    }
+}
+
+/// Range of addresses skipped by an `.org` directive.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Gap {
+    /// Address of the first skipped byte.
+    pub address: u64,
+    /// Number of bytes skipped.
+    pub size: u64,
+}
+
+/// Memory image made of extents separated by gaps.
+#[derive(Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct SparseImage {
+    /// Extents of the image, sorted by address.
+    pub extents: Vec<Extent>,
+    /// Gaps between the extents, sorted by address.
+    pub gaps: Vec<Gap>,
+}
+
+impl SparseImage {
+    /// Assembles `insns` at `address` into a sparse image.
+    ///
+    /// Gaps are never allocated, so `.org` can jump across gigabytes of address space.
+    ///
+    /// Returns the error of the first statement that could not be assembled, if any.
+    pub fn assemble(engine: &Keystone, insns: &str, address: u64) -> Result<Self> {
+        let stmts = layout::parse(engine.arch(), insns);
+        let lay = layout::layout(engine, &stmts, address, |_| None);
+        let mut image = SparseImage::default();
+        for placed in lay.placed {
+            let bytes = placed.bytes?;
+            let end = placed.address + bytes.len() as u64;
+            if !bytes.is_empty() {
+                match image.extents.last_mut() {
+                    Some(last) if last.end() == placed.address => last.bytes.extend(bytes),
+                    _ => image.extents.push(Extent {
+                        address: placed.address,
+                        bytes,
+                    }),
+                }
+            }
+            if placed.gap > 0 {
+                image.gaps.push(Gap {
+                    address: end,
+                    size: placed.gap,
+                });
+            }
+        }
+        Ok(image)
+    }
+
+    /// Returns the address of the first byte of the image, or `None` if it's empty.
+    pub fn start(&self) -> Option<u64> {
+        self.extents.first().map(|e| e.address)
+    }
+
+    /// Returns the address following the last byte of the image, or `None` if it's empty.
+    pub fn end(&self) -> Option<u64> {
+        self.extents.last().map(Extent::end)
+    }
+
+    /// Writes the extents of the image onto `image`, which holds the memory starting at `base`.
+    /// Gaps are left untouched.
+    ///
+    /// Returns [`MiscError::AddressRange`] without modifying `image` if an extent doesn't fit in
+    /// it.
+    pub fn merge_onto(&self, image: &mut [u8], base: u64) -> Result<()> {
+        let range = |e: &Extent| {
+            let start = usize::try_from(e.address.checked_sub(base)?).ok()?;
+            let end = start.checked_add(e.bytes.len())?;
+            (end <= image.len()).then_some(start..end)
+        };
+        let ranges = self
+            .extents
+            .iter()
+            .map(range)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(MiscError::AddressRange)?;
+        for (extent, range) in self.extents.iter().zip(ranges) {
+            image[range].copy_from_slice(&extent.bytes);
+        }
+        Ok(())
+    }
+
+    /// Returns the image as a contiguous buffer starting at [`SparseImage::start`], in which the
+    /// gaps are filled with `fill`.
+    pub fn to_raw(&self, fill: u8) -> Vec<u8> {
+        let (Some(start), Some(end)) = (self.start(), self.end()) else {
+            return vec![];
+        };
+        let mut raw = vec![fill; (end - start) as usize];
+        for extent in &self.extents {
+            let offset = (extent.address - start) as usize;
+            raw[offset..offset + extent.bytes.len()].copy_from_slice(&extent.bytes);
+        }
+        raw
+    }
+
+    /// Writes the image to the file at `path`, as returned by [`SparseImage::to_raw`].
+    pub fn write_raw(&self, path: impl AsRef<Path>, fill: u8) -> std::io::Result<()> {
+        std::fs::write(path, self.to_raw(fill))
+    }
+
+    /// Serializes the image in the Intel HEX format.
+    ///
+    /// Returns [`MiscError::AddressRange`] if the image extends beyond 4 GiB, which the format
+    /// can't represent.
+    pub fn to_ihex(&self) -> Result<String> {
+        if self.end().is_some_and(|end| end > 1 << 32) {
+            return Err(MiscError::AddressRange)?;
+        }
+        let mut out = String::new();
+        let mut upper = 0;
+        for extent in &self.extents {
+            let mut address = extent.address;
+            let mut data = &extent.bytes[..];
+            while !data.is_empty() {
+                // Records can't cross a 64 KiB boundary.
+                let room = 0x10000 - (address & 0xffff) as usize;
+                let len = data.len().min(IHEX_RECORD_SIZE).min(room);
+                if address >> 16 != upper {
+                    upper = address >> 16;
+                    ihex_record(&mut out, 0, 4, &(upper as u16).to_be_bytes());
+                }
+                ihex_record(&mut out, address as u16, 0, &data[..len]);
+                address += len as u64;
+                data = &data[len..];
+            }
+        }
+        ihex_record(&mut out, 0, 1, &[]);
+        Ok(out)
+    }
+}
+
+/// Appends an Intel HEX record to `out`.
+fn ihex_record(out: &mut String, offset: u16, kind: u8, data: &[u8]) {
+    let header = [data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+    let sum = header
+        .iter()
+        .chain(data)
+        .fold(0u8, |acc, &b| acc.wrapping_add(b));
+    out.push(':');
+    for b in header.iter().chain(data) {
+        out.push_str(&format!("{:02X}", b));
+    }
+    out.push_str(&format!("{:02X}\n", sum.wrapping_neg()));
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi;
+
+    fn image() -> SparseImage {
+        SparseImage {
+            extents: vec![
+                Extent {
+                    address: 0xfffe,
+                    bytes: vec![0x90, 0x90, 0xc3],
+                },
+                Extent {
+                    address: 0x10010,
+                    bytes: vec![0xcc],
+                },
+            ],
+            gaps: vec![Gap {
+                address: 0x10001,
+                size: 0xf,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_raw() {
+        let image = image();
+        let raw = image.to_raw(0xff);
+        assert_eq!(raw.len(), 0x13);
+        assert_eq!(&raw[..4], &[0x90, 0x90, 0xc3, 0xff]);
+        assert_eq!(raw[0x12], 0xcc);
+
+        let mut memory = vec![0; 0x30];
+        image.merge_onto(&mut memory, 0xfff0).unwrap();
+        assert_eq!(&memory[0xe..0x12], &[0x90, 0x90, 0xc3, 0x00]);
+        assert_eq!(memory[0x20], 0xcc);
+        assert_eq!(
+            image.merge_onto(&mut memory, 0x10000),
+            Err(MiscError::AddressRange.into())
+        );
+    }
+
+    #[test]
+    fn test_ihex() {
+        assert_eq!(
+            image().to_ihex().unwrap(),
+            ":02FFFE009090E1\n\
+             :020000040001F9\n\
+             :01000000C33C\n\
+             :01001000CC23\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn test_assemble() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let image = SparseImage::assemble(&engine, "nop\n.org 0x10\nret", 0x1000).unwrap();
+        assert_eq!(
+            image.extents,
+            vec![
+                Extent {
+                    address: 0x1000,
+                    bytes: vec![0x90],
+                },
+                Extent {
+                    address: 0x1010,
+                    bytes: vec![0xc3],
+                },
+            ]
+        );
+        assert_eq!(
+            image.gaps,
+            vec![Gap {
+                address: 0x1001,
+                size: 0xf,
+            }]
+        );
+
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_64).unwrap();
+        let image = SparseImage::assemble(&engine, "nop\n.org 0x400000000\nret", 0).unwrap();
+        assert_eq!(
+            image.gaps,
+            vec![Gap {
+                address: 1,
+                size: 0x3_ffff_ffff,
+            }]
+        );
+        assert_eq!(
+            image.extents[1],
+            Extent {
+                address: 0x4_0000_0000,
+                bytes: vec![0xc3],
+            }
+        );
+    }
+}
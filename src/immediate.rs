@@ -0,0 +1,424 @@
+//! Immediate encodability checks.
+//!
+//! Whether a value can be used as an immediate depends on the instruction form and on the
+//! architecture: ARM only accepts 8-bit values rotated by an even amount, AArch64 logical
+//! instructions only accept repeating bit patterns, x86 picks between a sign-extended `imm8` and
+//! an `imm32`, etc. Instead of reimplementing each of these rules, this module assembles the
+//! candidate form through [`Keystone`] and classifies the result.
+//!
+//! ```no_run
+//! use keystone_engine::immediate::{Encodability, ImmediateChecker, ImmediateForm};
+//! use keystone_engine::Mode;
+//!
+//! let mut checker = ImmediateChecker::new();
+//! let res = checker
+//!     .check(Mode::ARM, ImmediateForm::ArmModified, 0xff00)
+//!     .expect("Could not check immediate");
+//! assert_eq!(res, Encodability::Full);
+//! ```
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::{ffi, Keystone, KeystoneError, Result};
+
+// -----------------------------------------------------------------------------------------------
+// Forms
+// -----------------------------------------------------------------------------------------------
+
+/// Instruction forms an immediate value can be checked against.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ImmediateForm {
+    /// ARM/Thumb-2 data-processing modified immediate (e.g. `eor r0, r0, #imm`).
+    ArmModified,
+    /// AArch64 32-bit logical bitmask immediate (e.g. `and w0, w0, #imm`).
+    Arm64Logical32,
+    /// AArch64 64-bit logical bitmask immediate (e.g. `and x0, x0, #imm`).
+    Arm64Logical64,
+    /// AArch64 arithmetic immediate, 12 bits optionally shifted by 12 (e.g. `add x0, x0, #imm`).
+    Arm64Arithmetic,
+    /// X86 ALU immediate, either a sign-extended `imm8` or a full-sized immediate (e.g.
+    /// `add ecx, imm`).
+    X86Alu,
+    /// MIPS 16-bit signed immediate (e.g. `addiu $t0, $t0, imm`).
+    MipsSigned16,
+}
+
+impl ImmediateForm {
+    /// Returns the architecture the form belongs to.
+    pub fn arch(self) -> ffi::Arch {
+        match self {
+            ImmediateForm::ArmModified => ffi::Arch::ARM,
+            ImmediateForm::Arm64Logical32
+            | ImmediateForm::Arm64Logical64
+            | ImmediateForm::Arm64Arithmetic => ffi::Arch::ARM64,
+            ImmediateForm::X86Alu => ffi::Arch::X86,
+            ImmediateForm::MipsSigned16 => ffi::Arch::MIPS,
+        }
+    }
+
+    /// Renders the candidate instruction for `value`, or returns `None` if the value is outside
+    /// of the operand's domain (e.g. a 64-bit value for a 32-bit register).
+    fn render(self, mode: ffi::Mode, value: i64) -> Option<String> {
+        let fits_32 = i32::try_from(value).is_ok() || u32::try_from(value).is_ok();
+        match self {
+            ImmediateForm::ArmModified if !fits_32 => None,
+            ImmediateForm::ArmModified if mode.contains(ffi::Mode::THUMB) => {
+                Some(format!("eor.w r0, r0, #{:#x}", value as u32))
+            }
+            ImmediateForm::ArmModified => Some(format!("eor r0, r0, #{:#x}", value as u32)),
+            ImmediateForm::Arm64Logical32 if !fits_32 => None,
+            ImmediateForm::Arm64Logical32 => Some(format!("and w0, w0, #{:#x}", value as u32)),
+            ImmediateForm::Arm64Logical64 => Some(format!("and x0, x0, #{:#x}", value as u64)),
+            ImmediateForm::Arm64Arithmetic => Some(format!("add x0, x0, #{}", value)),
+            ImmediateForm::X86Alu => {
+                let (reg, bits) = x86_operand(mode);
+                if bits < 64 && (value < -(1i64 << (bits - 1)) || value >= 1i64 << bits) {
+                    return None;
+                }
+                Some(format!("add {}, {}", reg, value))
+            }
+            ImmediateForm::MipsSigned16 => Some(format!("addiu $t0, $t0, {}", value)),
+        }
+    }
+
+    /// Classifies the encoding produced for the candidate instruction rendered with `value`.
+    fn classify(self, mode: ffi::Mode, value: i64, bytes: &[u8]) -> Encodability {
+        match self {
+            // Fixed-width forms: anything else than a single instruction means the assembler
+            // expanded the statement into a sequence. The immediate is then decoded from the
+            // instruction and compared to the original value to detect silent rewrites.
+            ImmediateForm::ArmModified
+            | ImmediateForm::Arm64Logical32
+            | ImmediateForm::Arm64Logical64
+            | ImmediateForm::Arm64Arithmetic
+            | ImmediateForm::MipsSigned16 => {
+                let expected = match self {
+                    ImmediateForm::ArmModified | ImmediateForm::Arm64Logical32 => {
+                        value as u32 as i64
+                    }
+                    _ => value,
+                };
+                if self.decode(mode, bytes) == Some(expected) {
+                    Encodability::Full
+                } else {
+                    Encodability::Unencodable
+                }
+            }
+            // The immediate is the last field of `add r/m, imm`, it's extracted and compared to
+            // the original value to detect silent truncations.
+            ImmediateForm::X86Alu => {
+                let (_, bits) = x86_operand(mode);
+                // Unsigned values are wrapped to the operand size, e.g. `0xffffffff` is `-1` for
+                // a 32-bit register.
+                let (value, full) = match bits {
+                    16 => (value as i16 as i64, 2),
+                    32 => (value as i32 as i64, 4),
+                    _ => (value, 4),
+                };
+                if trailing_imm(bytes, 1) == Some(value) {
+                    Encodability::Short
+                } else if trailing_imm(bytes, full) == Some(value) {
+                    Encodability::Full
+                } else {
+                    Encodability::Unencodable
+                }
+            }
+        }
+    }
+
+    /// Decodes the immediate of a fixed-width candidate instruction, or returns `None` if
+    /// `bytes` isn't a single instruction of the form.
+    fn decode(self, mode: ffi::Mode, bytes: &[u8]) -> Option<i64> {
+        let bytes: [u8; 4] = bytes.try_into().ok()?;
+        let big_endian = mode.contains(ffi::Mode::BIG_ENDIAN);
+        let word = match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        };
+        match self {
+            // Thumb-2 instructions are stored as two halfwords, the first one holding the
+            // opcode.
+            ImmediateForm::ArmModified if mode.contains(ffi::Mode::THUMB) => {
+                let half = |b: [u8; 2]| match big_endian {
+                    true => u16::from_be_bytes(b) as u32,
+                    false => u16::from_le_bytes(b) as u32,
+                };
+                let (hi, lo) = (half([bytes[0], bytes[1]]), half([bytes[2], bytes[3]]));
+                let imm12 = (hi >> 10 & 1) << 11 | (lo >> 12 & 7) << 8 | lo & 0xff;
+                Some(thumb_expand_imm(imm12) as i64)
+            }
+            ImmediateForm::ArmModified => {
+                let (rot, imm8) = (word >> 8 & 0xf, word & 0xff);
+                Some(imm8.rotate_right(2 * rot) as i64)
+            }
+            ImmediateForm::Arm64Logical32 | ImmediateForm::Arm64Logical64 => {
+                let size = match self {
+                    ImmediateForm::Arm64Logical32 => 32,
+                    _ => 64,
+                };
+                decode_bit_masks(word >> 22 & 1, word >> 16 & 0x3f, word >> 10 & 0x3f, size)
+                    .map(|imm| imm as i64)
+            }
+            // The assembler turns additions of negative values into subtractions.
+            ImmediateForm::Arm64Arithmetic => {
+                let imm = ((word >> 10 & 0xfff) << (12 * (word >> 22 & 1))) as i64;
+                Some(if word >> 30 & 1 == 1 { -imm } else { imm })
+            }
+            ImmediateForm::MipsSigned16 => Some(word as u16 as i16 as i64),
+            ImmediateForm::X86Alu => None,
+        }
+    }
+}
+
+/// Expands a Thumb-2 modified immediate (`ThumbExpandImm` in the Arm ARM).
+fn thumb_expand_imm(imm12: u32) -> u32 {
+    let imm8 = imm12 & 0xff;
+    if imm12 >> 10 == 0 {
+        match imm12 >> 8 & 3 {
+            0 => imm8,
+            1 => imm8 << 16 | imm8,
+            2 => imm8 << 24 | imm8 << 8,
+            _ => imm8 * 0x01010101,
+        }
+    } else {
+        (0x80 | imm12 & 0x7f).rotate_right(imm12 >> 7)
+    }
+}
+
+/// Decodes an AArch64 logical immediate of `size` bits (`DecodeBitMasks` in the Arm ARM).
+fn decode_bit_masks(n: u32, immr: u32, imms: u32, size: u32) -> Option<u64> {
+    let combined = n << 6 | (!imms & 0x3f);
+    if combined == 0 {
+        return None;
+    }
+    let esize = 1u32 << (31 - combined.leading_zeros());
+    let levels = esize - 1;
+    let (s, r) = (imms & levels, immr & levels);
+    if s == levels || esize > size {
+        return None;
+    }
+    let mask = if esize == 64 {
+        u64::MAX
+    } else {
+        (1u64 << esize) - 1
+    };
+    let welem = (1u64 << (s + 1)) - 1;
+    let elem = (welem >> r | welem << ((esize - r) % esize)) & mask;
+    let mut imm = 0;
+    for i in 0..size / esize {
+        imm |= elem << (i * esize);
+    }
+    Some(imm)
+}
+
+/// Returns `true` if `error` means that the immediate doesn't fit the form, as opposed to errors
+/// that prevent the check altogether.
+fn is_range_error(error: &KeystoneError) -> bool {
+    match error {
+        KeystoneError::Engine(e) => {
+            e.is_operand()
+                || matches!(
+                    *e,
+                    ffi::Error::ASM_INSN_UNSUPPORTED
+                        | ffi::Error::ASM_FIXUP_INVALID
+                        | ffi::Error::ASM_DIRECTIVE_VALUE_RANGE
+                )
+        }
+        KeystoneError::Misc(_) => false,
+    }
+}
+
+/// Returns the register used by the x86 candidate instructions and its size in bits.
+///
+/// `ecx` and its variants are used rather than the accumulator, which has a dedicated short
+/// encoding without `imm8`.
+fn x86_operand(mode: ffi::Mode) -> (&'static str, u32) {
+    if mode.contains(ffi::Mode::MODE_64) {
+        ("rcx", 64)
+    } else if mode.contains(ffi::Mode::MODE_32) {
+        ("ecx", 32)
+    } else {
+        ("cx", 16)
+    }
+}
+
+/// Sign-extends the last `size` bytes of a little-endian encoding.
+fn trailing_imm(bytes: &[u8], size: usize) -> Option<i64> {
+    let start = bytes.len().checked_sub(size)?;
+    let mut buf = [0u8; 8];
+    buf[..size].copy_from_slice(&bytes[start..]);
+    let shift = 64 - 8 * size as u32;
+    Some(i64::from_le_bytes(buf) << shift >> shift)
+}
+
+// -----------------------------------------------------------------------------------------------
+// Checker
+// -----------------------------------------------------------------------------------------------
+
+/// Result of an encodability check.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Encodability {
+    /// The value fits in the short immediate field of the form (e.g. x86 `imm8`).
+    Short,
+    /// The value fits in the immediate field of the form.
+    Full,
+    /// The value can't be encoded as an immediate in this form.
+    Unencodable,
+}
+
+impl Encodability {
+    /// Returns `true` if the value can be encoded as an immediate.
+    pub fn is_encodable(self) -> bool {
+        self != Encodability::Unencodable
+    }
+}
+
+/// Checks immediate encodability and caches the results per engine configuration.
+///
+/// The checker owns one Keystone instance per architecture and mode it has been queried for, all
+/// of them using the default syntax.
+#[derive(Debug, Default)]
+pub struct ImmediateChecker {
+    /// Keystone instances, indexed by their configuration.
+    engines: HashMap<(ffi::Arch, ffi::Mode), Keystone>,
+    /// Results of the previous checks.
+    cache: HashMap<(ffi::Mode, ImmediateForm, i64), Encodability>,
+}
+
+impl ImmediateChecker {
+    /// Creates a new checker with an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks if `value` can be encoded as an immediate of `form`, for the architecture of the
+    /// form in the given mode.
+    pub fn check(
+        &mut self,
+        mode: ffi::Mode,
+        form: ImmediateForm,
+        value: i64,
+    ) -> Result<Encodability> {
+        if let Some(&res) = self.cache.get(&(mode, form, value)) {
+            return Ok(res);
+        }
+        let arch = form.arch();
+        let res = match form.render(mode, value) {
+            Some(insn) => {
+                let engine = match self.engines.entry((arch, mode)) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(Keystone::new(arch, mode)?),
+                };
+                // Assembly errors are the expected outcome for values that don't fit, only
+                // errors that prevent the check altogether are propagated.
+                match engine.asm(insn, 0) {
+                    Ok(output) => form.classify(mode, value, &output.bytes),
+                    Err(e) if is_range_error(&e) => Encodability::Unencodable,
+                    Err(e) => return Err(e),
+                }
+            }
+            None => Encodability::Unencodable,
+        };
+        self.cache.insert((mode, form, value), res);
+        Ok(res)
+    }
+
+    /// Returns the first form of `forms` that can encode `value`, along with its encodability.
+    pub fn pick(
+        &mut self,
+        mode: ffi::Mode,
+        forms: &[ImmediateForm],
+        value: i64,
+    ) -> Result<Option<(ImmediateForm, Encodability)>> {
+        for &form in forms {
+            let res = self.check(mode, form, value)?;
+            if res.is_encodable() {
+                return Ok(Some((form, res)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Clears the cached results.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mode;
+
+    #[test]
+    fn test_trailing_imm() {
+        assert_eq!(trailing_imm(&[0x83, 0xc1, 0xff], 1), Some(-1));
+        assert_eq!(
+            trailing_imm(&[0x81, 0xc1, 0x00, 0x01, 0x00, 0x00], 4),
+            Some(0x100)
+        );
+        assert_eq!(trailing_imm(&[0x90], 4), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        // eor r0, r0, #0xff00
+        let form = ImmediateForm::ArmModified;
+        assert_eq!(
+            form.decode(Mode::ARM, &[0xff, 0x0c, 0x20, 0xe2]),
+            Some(0xff00)
+        );
+        // eor.w r0, r0, #0xff00ff00
+        let bytes = [0x80, 0xf0, 0xff, 0x20];
+        assert_eq!(form.decode(Mode::THUMB, &bytes), Some(0xff00ff00));
+        // and x0, x0, #0x5555555555555555
+        let form = ImmediateForm::Arm64Logical64;
+        let bytes = [0x00, 0xf0, 0x00, 0x92];
+        assert_eq!(
+            form.decode(Mode::LITTLE_ENDIAN, &bytes),
+            Some(0x5555555555555555)
+        );
+        // sub x0, x0, #0x1, lsl #12
+        let form = ImmediateForm::Arm64Arithmetic;
+        let bytes = [0x00, 0x04, 0x40, 0xd1];
+        assert_eq!(form.decode(Mode::LITTLE_ENDIAN, &bytes), Some(-0x1000));
+        // addiu $t0, $t0, -1
+        let form = ImmediateForm::MipsSigned16;
+        let bytes = [0xff, 0xff, 0x08, 0x25];
+        assert_eq!(form.decode(Mode::MIPS32, &bytes), Some(-1));
+        assert_eq!(form.decode(Mode::MIPS32, &bytes[..2]), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut checker = ImmediateChecker::new();
+        // ARM modified immediates.
+        let res = checker.check(Mode::ARM, ImmediateForm::ArmModified, 0xff00);
+        assert_eq!(res, Ok(Encodability::Full));
+        let res = checker.check(Mode::ARM, ImmediateForm::ArmModified, 0x101);
+        assert_eq!(res, Ok(Encodability::Unencodable));
+        // AArch64 logical immediates.
+        let form = ImmediateForm::Arm64Logical64;
+        let res = checker.check(Mode::LITTLE_ENDIAN, form, 0x5555555555555555);
+        assert_eq!(res, Ok(Encodability::Full));
+        let res = checker.check(Mode::LITTLE_ENDIAN, form, 0x1234);
+        assert_eq!(res, Ok(Encodability::Unencodable));
+        // X86 imm8 vs imm32.
+        let form = ImmediateForm::X86Alu;
+        let res = checker.check(Mode::MODE_64, form, -128);
+        assert_eq!(res, Ok(Encodability::Short));
+        let res = checker.check(Mode::MODE_64, form, 0x1000);
+        assert_eq!(res, Ok(Encodability::Full));
+        let res = checker.check(Mode::MODE_64, form, 0x100000000);
+        assert_eq!(res, Ok(Encodability::Unencodable));
+        // AArch64 additions of negative values are encoded as subtractions.
+        let form = ImmediateForm::Arm64Arithmetic;
+        let res = checker.check(Mode::LITTLE_ENDIAN, form, -0x10);
+        assert_eq!(res, Ok(Encodability::Full));
+    }
+}
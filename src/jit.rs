@@ -0,0 +1,185 @@
+//! Assembling code into executable memory (Linux on x86 and x86-64 only).
+//!
+//! [`JitCode`] maps memory, assembles the source at the address of the mapping, so that absolute
+//! references are resolved correctly, and copies the result into it. The mapping is then made
+//! read-only and executable, following W^X, and is unmapped when the [`JitCode`] is dropped.
+//!
+//! The module is limited to x86 hosts, whose instruction cache is coherent with stores, so that
+//! the code can be executed without flushing the cache.
+//!
+//! ```no_run
+//! use keystone_engine::jit::JitCode;
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_64).expect("Could not initialize Keystone");
+//! let code = JitCode::new(&engine, "lea rax, [rdi + rsi]; ret").expect("Could not assemble");
+//! let add = unsafe { code.as_fn::<extern "C" fn(u64, u64) -> u64>() };
+//! assert_eq!(add(1, 2), 3);
+//! ```
+
+use crate::{ffi, Keystone, KeystoneOutput, MiscError, Result};
+
+use libc::*;
+
+/// Maximum number of attempts at fitting the code in the mapping.
+///
+/// The size of the code can change with its address, in which case a larger mapping is needed.
+const MAX_ATTEMPTS: usize = 4;
+
+/// Mode of the code that can be executed on the host.
+#[cfg(target_arch = "x86_64")]
+const HOST_MODE: ffi::Mode = ffi::Mode::MODE_64;
+#[cfg(target_arch = "x86")]
+const HOST_MODE: ffi::Mode = ffi::Mode::MODE_32;
+
+/// Anonymous memory mapping, unmapped on drop.
+#[derive(Debug)]
+struct Mapping {
+    /// Start of the mapping.
+    ptr: *mut c_void,
+    /// Size of the mapping, a multiple of the page size.
+    len: usize,
+}
+
+impl Mapping {
+    /// Maps at least `size` bytes of readable and writable memory.
+    fn new(size: usize) -> Result<Self> {
+        let page = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+        let len = size.max(1).div_ceil(page) * page;
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == MAP_FAILED {
+            return Err(MiscError::Jit)?;
+        }
+        Ok(Mapping { ptr, len })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// Code assembled into executable memory.
+#[derive(Debug)]
+pub struct JitCode {
+    /// Memory holding the code.
+    mapping: Mapping,
+    /// Result of the assembly.
+    output: KeystoneOutput,
+}
+
+// The mapping is never written to once the code has been copied into it.
+unsafe impl Send for JitCode {}
+unsafe impl Sync for JitCode {}
+
+impl JitCode {
+    /// Assembles `insns` into a new executable mapping.
+    ///
+    /// Returns [`MiscError::Jit`] if the engine doesn't target the host, i.e. x86 in the mode
+    /// of the host, or if the memory could not be mapped or protected.
+    pub fn new(engine: &Keystone, insns: &str) -> Result<Self> {
+        if engine.arch() != ffi::Arch::X86 || !engine.mode().contains(HOST_MODE) {
+            return Err(MiscError::Jit)?;
+        }
+        let mut size = engine.asm(insns.to_string(), 0)?.bytes.len();
+        for _ in 0..MAX_ATTEMPTS {
+            let mapping = Mapping::new(size)?;
+            let output = engine.asm(insns.to_string(), mapping.ptr as u64)?;
+            if output.bytes.len() > mapping.len {
+                size = output.bytes.len();
+                continue;
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    output.bytes.as_ptr(),
+                    mapping.ptr as *mut u8,
+                    output.bytes.len(),
+                );
+                if mprotect(mapping.ptr, mapping.len, PROT_READ | PROT_EXEC) != 0 {
+                    return Err(MiscError::Jit)?;
+                }
+            }
+            return Ok(JitCode { mapping, output });
+        }
+        Err(MiscError::Jit)?
+    }
+
+    /// Returns the address of the code.
+    pub fn address(&self) -> u64 {
+        self.mapping.ptr as u64
+    }
+
+    /// Returns the result of the assembly.
+    pub fn output(&self) -> &KeystoneOutput {
+        &self.output
+    }
+
+    /// Returns the code, as stored in executable memory.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.mapping.ptr as *const u8, self.output.bytes.len())
+        }
+    }
+
+    /// Returns the code as a function pointer of type `F`, e.g. `extern "C" fn(u64) -> u64`.
+    ///
+    /// # Safety
+    ///
+    /// `F` must be a function pointer type whose signature and calling convention match the
+    /// code, and the pointer must not be called once `self` has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `F` doesn't have the size of a pointer.
+    pub unsafe fn as_fn<F: Copy>(&self) -> F {
+        assert_eq!(
+            std::mem::size_of::<F>(),
+            std::mem::size_of::<*const c_void>(),
+            "F must be a function pointer"
+        );
+        std::mem::transmute_copy(&self.mapping.ptr)
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jit() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_64).unwrap();
+        let code = JitCode::new(&engine, "lea rax, [rdi + rsi]\nret").unwrap();
+        assert_eq!(code.as_bytes(), code.output().bytes);
+        let add = unsafe { code.as_fn::<extern "C" fn(u64, u64) -> u64>() };
+        assert_eq!(add(40, 2), 42);
+
+        // Absolute references are resolved against the address of the mapping.
+        engine
+            .option(ffi::OptionType::SYNTAX, ffi::OptionValue::SYNTAX_ATT)
+            .unwrap();
+        let code = JitCode::new(&engine, "movabsq $end, %rax\nret\nend:").unwrap();
+        let end = unsafe { code.as_fn::<extern "C" fn() -> u64>() };
+        assert_eq!(end(), code.address() + code.as_bytes().len() as u64);
+
+        // Code for another architecture or mode can't be executed.
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        assert_eq!(
+            JitCode::new(&engine, "ret").unwrap_err(),
+            MiscError::Jit.into()
+        );
+    }
+}
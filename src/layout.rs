@@ -0,0 +1,603 @@
+//! Statement-level layout of assembly programs.
+//!
+//! Keystone only returns the encoding of a whole program. Features that need to know which bytes
+//! belong to which statement split the program into [`Statement`]s and assemble each of them at
+//! its final address, using a symbol resolver to provide the addresses of labels defined by the
+//! other statements.
+//!
+//! Labels are placed iteratively: instructions referencing a label can change size when the
+//! label moves (e.g. short and near jumps on x86), so the program is laid out again until label
+//! addresses are stable.
+//!
+//! Multi-line constructs (`.macro`, `.rept`, `.if`, etc.) are kept together as a single
+//! statement. Numeric local labels (`1:`, `1b`, `1f`) are not supported.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{ffi, Keystone, MiscError, Result};
+
+/// Maximum number of layout passes before giving up on label convergence.
+pub(crate) const MAX_PASSES: usize = 16;
+
+/// Largest `.org` gap that is zero-filled when a contiguous output is needed (256 MiB).
+const MAX_GAP: usize = 1 << 28;
+
+// -----------------------------------------------------------------------------------------------
+// Statements
+// -----------------------------------------------------------------------------------------------
+
+/// Kind of a statement, which determines how it is laid out.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum StatementKind {
+    /// No code, only a label and/or a comment.
+    Empty,
+    /// Code that doesn't emit bytes but affects the statements after it (equates, macro
+    /// definitions, mode directives). It is replayed before every statement that follows.
+    Definition,
+    /// Alignment directive, whose size depends on the address of the statement.
+    Align,
+    /// `.org` directive.
+    Org,
+    /// Instructions and data directives.
+    Code,
+}
+
+/// Statement of an assembly program.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Statement {
+    /// Line number of the statement in the source, starting at 1.
+    pub(crate) line: usize,
+    /// Column of the statement in its line, starting at 1.
+    pub(crate) column: usize,
+    /// Label defined by the statement, if any.
+    pub(crate) label: Option<String>,
+    /// Code of the statement, without its label and comment.
+    pub(crate) code: String,
+    /// Comment following the statement, including its delimiter.
+    pub(crate) comment: Option<String>,
+    /// Kind of the statement.
+    pub(crate) kind: StatementKind,
+}
+
+impl Statement {
+    /// Creates a statement and infers its kind from `code`.
+    pub(crate) fn new(line: usize, column: usize, label: Option<String>, code: String) -> Self {
+        let kind = StatementKind::of(&code);
+        Statement {
+            line,
+            column,
+            label,
+            code,
+            comment: None,
+            kind,
+        }
+    }
+
+    /// Returns the statement as assembly source, on a single line unless it is a block.
+    pub(crate) fn to_source(&self) -> String {
+        let mut out = String::new();
+        if let Some(label) = &self.label {
+            out.push_str(label);
+            out.push(':');
+            if !self.code.is_empty() {
+                out.push(' ');
+            }
+        }
+        out.push_str(&self.code);
+        if let Some(comment) = &self.comment {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            out.push_str(comment);
+        }
+        out
+    }
+}
+
+impl StatementKind {
+    /// Infers the kind of a statement from its code.
+    fn of(code: &str) -> Self {
+        let code = code.trim();
+        if code.is_empty() {
+            return StatementKind::Empty;
+        }
+        let word = first_word(code).to_ascii_lowercase();
+        match word.as_str() {
+            ".macro" | ".equ" | ".set" | ".equiv" | ".eqv" | ".code16" | ".code32" | ".code64"
+            | ".intel_syntax" | ".att_syntax" | ".arm" | ".thumb" | ".code" | ".syntax"
+            | ".arch" | ".cpu" | ".fpu" | ".nasm" => StatementKind::Definition,
+            ".align" | ".balign" | ".p2align" => StatementKind::Align,
+            ".org" => StatementKind::Org,
+            // Assignments such as `len = 4`.
+            _ if code[word.len()..].trim_start().starts_with('=')
+                && !code[word.len()..].trim_start().starts_with("==")
+                && is_ident(&word) =>
+            {
+                StatementKind::Definition
+            }
+            _ => StatementKind::Code,
+        }
+    }
+}
+
+/// Returns the first whitespace-delimited word of `code`.
+fn first_word(code: &str) -> &str {
+    code.split(|c: char| c.is_whitespace() || c == ',')
+        .next()
+        .unwrap_or("")
+}
+
+/// Returns `true` if `s` is a valid symbol name.
+pub(crate) fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$' || c == '@')
+}
+
+/// Returns the comment delimiters of an architecture.
+fn comment_markers(arch: ffi::Arch) -> &'static [&'static str] {
+    match arch {
+        ffi::Arch::ARM => &["@", "//", "/*"],
+        ffi::Arch::ARM64 | ffi::Arch::HEXAGON => &["//", "/*"],
+        ffi::Arch::SPARC => &["!", "/*"],
+        _ => &["#", "/*"],
+    }
+}
+
+/// Returns the byte offset of the comment in `line`, if any.
+fn find_comment(arch: ffi::Arch, line: &str) -> Option<usize> {
+    let markers = comment_markers(arch);
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if markers.iter().any(|m| line[i..].starts_with(m)) => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Splits the code of a line on statement separators, returning each piece with its offset.
+fn split_separators(code: &str) -> Vec<(usize, &str)> {
+    let mut pieces = vec![];
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in code.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => {
+                pieces.push((start, &code[start..i]));
+                start = i + 1;
+            }
+            None => {}
+        }
+    }
+    pieces.push((start, &code[start..]));
+    pieces
+}
+
+/// Splits the label at the start of `code`, if any.
+fn split_label(code: &str) -> Option<(&str, &str)> {
+    let colon = code.find(':')?;
+    let label = code[..colon].trim_end();
+    if is_ident(label) && !code[colon + 1..].starts_with(':') {
+        Some((label, code[colon + 1..].trim_start()))
+    } else {
+        None
+    }
+}
+
+/// Returns the change in block nesting introduced by a statement.
+fn block_delta(code: &str) -> isize {
+    match first_word(code.trim()).to_ascii_lowercase().as_str() {
+        ".macro" | ".rept" | ".irp" | ".irpc" => 1,
+        w if w.starts_with(".if") => 1,
+        ".endm" | ".endr" | ".endif" => -1,
+        _ => 0,
+    }
+}
+
+/// Parses an assembly program into statements.
+pub(crate) fn parse(arch: ffi::Arch, source: &str) -> Vec<Statement> {
+    let mut statements: Vec<Statement> = vec![];
+    // Block currently being collected, along with its nesting depth.
+    let mut block: Option<(Statement, isize)> = None;
+    for (idx, line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        // Lines inside of a block are kept verbatim.
+        if let Some((stmt, depth)) = block.as_mut() {
+            let code_end = find_comment(arch, line).unwrap_or(line.len());
+            stmt.code.push('\n');
+            stmt.code.push_str(line);
+            *depth += split_separators(&line[..code_end])
+                .iter()
+                .map(|(_, p)| block_delta(p))
+                .sum::<isize>();
+            if *depth <= 0 {
+                statements.push(block.take().unwrap().0);
+            }
+            continue;
+        }
+        let code_end = find_comment(arch, line).unwrap_or(line.len());
+        let comment = line[code_end..].trim_end();
+        let first = statements.len();
+        for (offset, piece) in split_separators(&line[..code_end]) {
+            let mut rest = piece.trim();
+            let mut column = offset + piece.len() - piece.trim_start().len() + 1;
+            // A statement can start with several labels.
+            while let Some((label, tail)) = split_label(rest) {
+                let tail_column = column + rest.len() - tail.len();
+                if tail.is_empty() || split_label(tail).is_some() {
+                    statements.push(Statement::new(
+                        line_no,
+                        column,
+                        Some(label.to_string()),
+                        String::new(),
+                    ));
+                    rest = tail;
+                    column = tail_column;
+                    if tail.is_empty() {
+                        break;
+                    }
+                } else {
+                    let stmt =
+                        Statement::new(line_no, column, Some(label.to_string()), tail.to_string());
+                    rest = "";
+                    push_or_open(&mut statements, &mut block, stmt);
+                    break;
+                }
+            }
+            if !rest.is_empty() {
+                let stmt = Statement::new(line_no, column, None, rest.to_string());
+                push_or_open(&mut statements, &mut block, stmt);
+            }
+        }
+        // Comments are attached to the last statement of the line.
+        if !comment.is_empty() {
+            if let Some((stmt, _)) = block.as_mut() {
+                stmt.code.push(' ');
+                stmt.code.push_str(comment);
+            } else if statements.len() > first {
+                statements.last_mut().unwrap().comment = Some(comment.to_string());
+            } else {
+                let mut stmt = Statement::new(line_no, code_end + 1, None, String::new());
+                stmt.comment = Some(comment.to_string());
+                statements.push(stmt);
+            }
+        } else if statements.len() == first && block.is_none() {
+            statements.push(Statement::new(line_no, 1, None, String::new()));
+        }
+    }
+    // Unterminated blocks are handed to Keystone as they are, it reports the error.
+    if let Some((stmt, _)) = block {
+        statements.push(stmt);
+    }
+    statements
+}
+
+/// Pushes a statement, or starts collecting a block if the statement opens one.
+fn push_or_open(
+    statements: &mut Vec<Statement>,
+    block: &mut Option<(Statement, isize)>,
+    stmt: Statement,
+) {
+    match block {
+        Some((open, depth)) => {
+            *depth += block_delta(&stmt.code);
+            open.code.push_str("; ");
+            open.code.push_str(&stmt.to_source());
+            if *depth <= 0 {
+                statements.push(block.take().unwrap().0);
+            }
+        }
+        None => {
+            let depth = block_delta(&stmt.code);
+            if depth > 0 {
+                *block = Some((stmt, depth));
+            } else {
+                statements.push(stmt);
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Layout
+// -----------------------------------------------------------------------------------------------
+
+/// Statement placed at its final address.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Placed {
+    /// Address of the statement.
+    pub(crate) address: u64,
+    /// Encoding of the statement, or the error returned while assembling it.
+    pub(crate) bytes: Result<Vec<u8>>,
+    /// Number of bytes skipped by an `.org` directive after the encoding. They aren't part of
+    /// `bytes`, so that large jumps don't need to be allocated.
+    pub(crate) gap: u64,
+}
+
+impl Placed {
+    /// Returns the encoding of the statement followed by the bytes it skips, zero-filled.
+    ///
+    /// Returns [`ffi::Error::ASM_DIRECTIVE_VALUE_RANGE`] if the gap is too large to be filled.
+    pub(crate) fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut bytes = self.bytes?;
+        let gap = usize::try_from(self.gap)
+            .ok()
+            .filter(|&gap| gap <= MAX_GAP)
+            .ok_or(ffi::Error::ASM_DIRECTIVE_VALUE_RANGE)?;
+        bytes.resize(bytes.len() + gap, 0);
+        Ok(bytes)
+    }
+}
+
+/// Layout of a program.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Layout {
+    /// Addresses of the labels defined by the program.
+    pub(crate) labels: HashMap<String, u64>,
+    /// Statements of the program, in the same order as the input statements.
+    pub(crate) placed: Vec<Placed>,
+}
+
+/// Parses the value of an `.org` directive, if it is a literal.
+fn org_value(code: &str) -> Option<u64> {
+    let arg = code.trim()[4..].split(',').next()?.trim();
+    parse_int(arg).and_then(|v| u64::try_from(v).ok())
+}
+
+/// Parses an integer literal (decimal, `0x`/`0b`/`0o` prefixed or `h`-suffixed hexadecimal).
+pub(crate) fn parse_int(s: &str) -> Option<i128> {
+    let s = s.trim().trim_start_matches('#').trim_start_matches('$');
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s.trim_start()),
+        None => (false, s),
+    };
+    let lower = s.to_ascii_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i128::from_str_radix(bin, 2).ok()?
+    } else if let Some(oct) = lower.strip_prefix("0o") {
+        i128::from_str_radix(oct, 8).ok()?
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        if !hex.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        i128::from_str_radix(hex, 16).ok()?
+    } else {
+        lower.parse::<i128>().ok()?
+    };
+    Some(if neg { -value } else { value })
+}
+
+/// Returns the alignment in bytes of an alignment directive, given the definitions preceding it.
+///
+/// The directive is assembled one byte past an aligned address, so that the size of the output
+/// is the alignment itself, whatever the unit of its argument on the architecture. A maximum
+/// number of bytes to skip smaller than the alignment isn't taken into account.
+fn alignment(engine: &Keystone, definitions: &str, code: &str) -> Result<u64> {
+    let insns = format!("{}.skip 1\n{}", definitions, code);
+    Ok(engine.asm(insns, 0)?.bytes.len() as u64)
+}
+
+/// Assembles every statement of a program at its final address.
+///
+/// Symbols that are neither labels of the program nor equates are resolved with `resolver`.
+/// Statements referencing labels that are still moving after [`MAX_PASSES`] passes fail with
+/// [`ffi::Error::ASM_FRAGMENT_INVALID`], since they were assembled against stale addresses.
+pub(crate) fn layout<F>(
+    engine: &Keystone,
+    statements: &[Statement],
+    address: u64,
+    mut resolver: F,
+) -> Layout
+where
+    F: FnMut(&str) -> Option<u64>,
+{
+    let defined: HashSet<&str> = statements
+        .iter()
+        .filter_map(|s| s.label.as_deref())
+        .collect();
+    let mut labels: HashMap<String, u64> = HashMap::new();
+    let mut placed = vec![];
+    // Labels referenced by each statement, with the address they resolved to.
+    let mut references: Vec<Vec<(String, u64)>> = vec![];
+    let mut stable = false;
+    for _ in 0..MAX_PASSES {
+        let mut next_labels = HashMap::new();
+        let mut definitions = String::new();
+        let mut addr = address;
+        placed = Vec::with_capacity(statements.len());
+        references.clear();
+        for stmt in statements {
+            let stmt_addr = addr;
+            let mut gap = 0;
+            let mut used = vec![];
+            if let Some(label) = &stmt.label {
+                next_labels.insert(label.clone(), addr);
+            }
+            let bytes = match stmt.kind {
+                StatementKind::Empty => Ok(vec![]),
+                StatementKind::Definition => {
+                    definitions.push_str(&stmt.code);
+                    definitions.push('\n');
+                    Ok(vec![])
+                }
+                StatementKind::Org => match org_value(&stmt.code).map(|t| address.checked_add(t)) {
+                    Some(Some(target)) if target >= addr => {
+                        gap = target - addr;
+                        Ok(vec![])
+                    }
+                    Some(_) => Err(ffi::Error::ASM_DIRECTIVE_VALUE_RANGE.into()),
+                    None => Err(ffi::Error::ASM_DIRECTIVE_INVALID.into()),
+                },
+                StatementKind::Align | StatementKind::Code => {
+                    // Labels that haven't been placed yet resolve to the current address, which
+                    // favors the shortest encodings on the first pass.
+                    let mut resolve = |sym: &str| {
+                        let value = match labels.get(sym) {
+                            Some(&value) => value,
+                            None if defined.contains(sym) => addr,
+                            None => return resolver(sym),
+                        };
+                        used.push((sym.to_string(), value));
+                        Some(value)
+                    };
+                    if stmt.kind == StatementKind::Align {
+                        // Keystone aligns relative to the start of its input, the statement is
+                        // preceded by padding to preserve the offset of `addr` in the alignment.
+                        alignment(engine, &definitions, &stmt.code).and_then(|align| {
+                            let pad = addr % align;
+                            let insns = format!("{}.skip {}\n{}", definitions, pad, stmt.code);
+                            engine
+                                .asm_with_resolver(insns, addr - pad, &mut resolve)
+                                .map(|out| out.bytes[pad as usize..].to_vec())
+                        })
+                    } else {
+                        let insns = format!("{}{}", definitions, stmt.code);
+                        engine
+                            .asm_with_resolver(insns, addr, &mut resolve)
+                            .map(|out| out.bytes)
+                    }
+                }
+            };
+            // Statements that don't fit in the address space fail, instead of wrapping around.
+            let bytes = bytes.and_then(|bytes| {
+                let next = (bytes.len() as u64)
+                    .checked_add(gap)
+                    .and_then(|size| addr.checked_add(size))
+                    .ok_or(MiscError::AddressRange)?;
+                addr = next;
+                Ok(bytes)
+            });
+            placed.push(Placed {
+                address: stmt_addr,
+                bytes,
+                gap,
+            });
+            references.push(used);
+        }
+        stable = next_labels == labels;
+        labels = next_labels;
+        if stable {
+            break;
+        }
+    }
+    if !stable {
+        for (placed, used) in placed.iter_mut().zip(&references) {
+            if used
+                .iter()
+                .any(|(sym, value)| labels.get(sym) != Some(value))
+            {
+                placed.bytes = Err(ffi::Error::ASM_FRAGMENT_INVALID.into());
+            }
+        }
+    }
+    Layout { labels, placed }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let stmts = parse(
+            ffi::Arch::X86,
+            "start: mov eax, 1 # one\n\
+             \n\
+             loop: dec eax; jnz loop\n\
+             .macro m\n\
+             nop\n\
+             .endm\n\
+             len = 4",
+        );
+        let codes: Vec<_> = stmts.iter().map(|s| s.code.as_str()).collect();
+        assert_eq!(
+            codes,
+            vec![
+                "mov eax, 1",
+                "",
+                "dec eax",
+                "jnz loop",
+                ".macro m\nnop\n.endm",
+                "len = 4"
+            ]
+        );
+        assert_eq!(stmts[0].label.as_deref(), Some("start"));
+        assert_eq!(stmts[0].comment.as_deref(), Some("# one"));
+        assert_eq!(stmts[2].label.as_deref(), Some("loop"));
+        assert_eq!(stmts[3].line, 3);
+        assert_eq!(stmts[3].column, 16);
+        assert_eq!(stmts[4].kind, StatementKind::Definition);
+        assert_eq!(stmts[5].kind, StatementKind::Definition);
+    }
+
+    #[test]
+    fn test_parse_int() {
+        assert_eq!(parse_int("0x10"), Some(16));
+        assert_eq!(parse_int("#-8"), Some(-8));
+        assert_eq!(parse_int("0ffh"), Some(255));
+        assert_eq!(parse_int("eax"), None);
+    }
+
+    #[test]
+    fn test_layout() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let stmts = parse(ffi::Arch::X86, "jmp end\nnop\nend: ret");
+        let jumps = layout(&engine, &stmts, 0x1000, |_| None);
+        assert_eq!(jumps.labels.get("end"), Some(&0x1003));
+        assert_eq!(jumps.placed[0].bytes, Ok(vec![0xeb, 0x01]));
+        assert_eq!(jumps.placed[2].address, 0x1003);
+        assert_eq!(jumps.placed[2].bytes, Ok(vec![0xc3]));
+
+        let stmts = parse(ffi::Arch::X86, "nop\n.balign 0x2000\nend: ret");
+        let aligned = layout(&engine, &stmts, 0x1000, |_| None);
+        assert_eq!(aligned.labels.get("end"), Some(&0x2000));
+
+        let stmts = parse(ffi::Arch::X86, "nop\n.org 0x10\nret");
+        let gap = layout(&engine, &stmts, 0x1000, |_| None);
+        assert_eq!(gap.placed[1].bytes, Ok(vec![]));
+        assert_eq!(gap.placed[1].gap, 0xf);
+        assert_eq!(gap.placed[2].address, 0x1010);
+        assert_eq!(gap.placed[1].clone().into_bytes(), Ok(vec![0; 0xf]));
+
+        let stmts = parse(ffi::Arch::X86, "nop\nret");
+        let top = layout(&engine, &stmts, u64::MAX, |_| None);
+        assert_eq!(top.placed[0].bytes, Err(MiscError::AddressRange.into()));
+
+        let stmts = parse(ffi::Arch::X86, ".org 0xffffffffffffffff\nret");
+        let org = layout(&engine, &stmts, 0x1000, |_| None);
+        assert_eq!(
+            org.placed[0].bytes,
+            Err(ffi::Error::ASM_DIRECTIVE_VALUE_RANGE.into())
+        );
+    }
+}
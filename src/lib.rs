@@ -2,7 +2,8 @@
 //!
 //! These Rust bindings are an alternative to the ones available in the
 //! [official repository](https://github.com/keystone-engine/keystone/tree/master/bindings/rust)
-//! and support version 0.9.2 of the Keystone Engine.
+//! and support version 0.9.2 of the Keystone Engine, as well as the other releases and builds
+//! sharing its API version (see [`version`]).
 //!
 //! ## Why Release New Bindings If Official Ones Exist?
 //!
@@ -30,6 +31,15 @@
 //! **Note:** You can use either the `build-from-src` feature to build the Keystone Engine or
 //!           `use-system-lib` if you have already installed Keystone on your system.
 //!
+//! **Note:** With the `dynamic-load` feature, Keystone isn't linked at build time. The library
+//!           is loaded at runtime from the path in the `KEYSTONE_LIBRARY` environment variable
+//!           or from the system search paths, unless another one is loaded with
+//!           [`ffi::load`] first.
+//!
+//! **Note:** When building from source, the `arch-*` features (e.g. `arch-x86`, `arch-arm64`)
+//!           restrict the architectures compiled into Keystone. All of them are built if none is
+//!           selected.
+//!
 //! You should now be able to run the following code:
 //!
 //! ```
@@ -43,12 +53,12 @@
 //!     .expect("Could not set option to nasm syntax");
 //!
 //! let result = engine
-//!     .asm("mov ah, 0x80".to_string(), 0)
+//!     .asm("mov ah, 0x80", 0)
 //!     .expect("Could not assemble");
 //!
 //! println!("ASM result: {}", result);
 //!
-//! if let Err(err) = engine.asm("INVALID".to_string(), 0) {
+//! if let Err(err) = engine.asm("INVALID", 0) {
 //!     println!("Error: {}", err);
 //! }
 //! ```
@@ -60,9 +70,24 @@
 //!  * [Rust bindings](https://github.com/keystone-engine/keystone/tree) by
 //!    [Remco Verhoef](mailto:remco@dutchcoders.io)
 
+pub mod badbytes;
+pub mod diagnostics;
+pub mod encoders;
+pub mod export;
 pub mod ffi;
+pub mod image;
+pub mod immediate;
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+pub mod jit;
+mod layout;
+pub mod listing;
+pub mod preprocess;
+pub mod sections;
+pub mod stackstr;
+pub mod syscalls;
+pub mod version;
 
-pub use ffi::{Arch, Error, Mode, OptionType, OptionValue};
+pub use ffi::{Arch, Error, ErrorKind, Mode, OptionType, OptionValue};
 
 use libc::*;
 
@@ -105,11 +130,97 @@ impl From<MiscError> for KeystoneError {
     }
 }
 
+// Errors are serialized with their numeric code (engine errors only), name, description and
+// payload (misc errors only), e.g.
+// `{"type": "engine", "code": 512, "name": "ASM_INVALIDOPERAND", "message": "...", "details": null}`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for KeystoneError {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("KeystoneError", 5)?;
+        match self {
+            KeystoneError::Engine(e) => {
+                s.serialize_field("type", "engine")?;
+                s.serialize_field("code", &Some(e.code()))?;
+                s.serialize_field("name", e.name())?;
+                s.serialize_field("message", &e.strerror())?;
+                s.serialize_field("details", &None::<serde_json::Value>)?;
+            }
+            KeystoneError::Misc(e) => {
+                s.serialize_field("type", "misc")?;
+                s.serialize_field("code", &None::<u32>)?;
+                s.serialize_field("name", e.name())?;
+                s.serialize_field("message", &e.to_string())?;
+                s.serialize_field("details", &e.details())?;
+            }
+        }
+        s.end()
+    }
+}
+
 /// Miscellaneous errors.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum MiscError {
     /// Error returned when a call to `ks_asm` fails.
     KsAsm,
+    /// Error returned when no encoding free of forbidden bytes could be found.
+    BadBytes,
+    /// Error returned when a system call isn't known for the requested ABI.
+    UnknownSyscall,
+    /// Error returned when a system call has more arguments than the ABI supports.
+    SyscallArgs,
+    /// Error returned when the Keystone library could not be loaded at runtime (see
+    /// [`ffi::load_default`] for details).
+    Library,
+    /// Error returned when executable memory could not be mapped.
+    Jit,
+    /// Error returned when an address is outside of the range of an image or output format, or
+    /// when sections overlap.
+    AddressRange,
+    /// Error returned when the input contains a NUL byte, at the given position.
+    InteriorNul(usize),
+    /// Error returned when the output of Keystone is too large to be represented.
+    OutputTooLarge,
+    /// Error returned when a symbol resolver panicked.
+    ResolverPanic,
+    /// Error returned when an option or a setting has an invalid value.
+    InvalidConfig,
+    /// Error returned when the input could not be read, with the kind of the I/O error. Only the
+    /// kind is kept, so that the error stays `Copy`, and the message of the I/O error is lost.
+    Io(std::io::ErrorKind),
+}
+
+impl MiscError {
+    /// Returns the name of the error, e.g. `InteriorNul`, which doesn't depend on its payload.
+    pub const fn name(self) -> &'static str {
+        match self {
+            MiscError::KsAsm => "KsAsm",
+            MiscError::BadBytes => "BadBytes",
+            MiscError::UnknownSyscall => "UnknownSyscall",
+            MiscError::SyscallArgs => "SyscallArgs",
+            MiscError::Library => "Library",
+            MiscError::Jit => "Jit",
+            MiscError::AddressRange => "AddressRange",
+            MiscError::InteriorNul(_) => "InteriorNul",
+            MiscError::OutputTooLarge => "OutputTooLarge",
+            MiscError::ResolverPanic => "ResolverPanic",
+            MiscError::InvalidConfig => "InvalidConfig",
+            MiscError::Io(_) => "Io",
+        }
+    }
+
+    /// Returns the payload of the error as a JSON object, if it has one.
+    #[cfg(feature = "serde")]
+    pub(crate) fn details(self) -> Option<serde_json::Value> {
+        match self {
+            MiscError::InteriorNul(pos) => Some(serde_json::json!({ "position": pos })),
+            MiscError::Io(kind) => Some(serde_json::json!({ "kind": format!("{:?}", kind) })),
+            _ => None,
+        }
+    }
 }
 
 impl std::error::Error for MiscError {}
@@ -118,6 +229,17 @@ impl std::fmt::Display for MiscError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             MiscError::KsAsm => write!(f, "an error occured while calling ks_asm"),
+            MiscError::BadBytes => write!(f, "could not avoid the forbidden bytes"),
+            MiscError::UnknownSyscall => write!(f, "unknown system call"),
+            MiscError::SyscallArgs => write!(f, "too many system call arguments"),
+            MiscError::Library => write!(f, "could not load the keystone library"),
+            MiscError::Jit => write!(f, "could not map executable memory"),
+            MiscError::AddressRange => write!(f, "address out of range"),
+            MiscError::InteriorNul(pos) => write!(f, "input contains a NUL byte at {}", pos),
+            MiscError::OutputTooLarge => write!(f, "output too large"),
+            MiscError::ResolverPanic => write!(f, "the symbol resolver panicked"),
+            MiscError::InvalidConfig => write!(f, "invalid configuration"),
+            MiscError::Io(kind) => write!(f, "could not read the input: {}", kind),
         }
     }
 }
@@ -126,6 +248,125 @@ impl std::fmt::Display for MiscError {
 // API
 // -----------------------------------------------------------------------------------------------
 
+/// Assembly source accepted by [`Keystone::asm`] and its variants.
+///
+/// Keystone expects a NUL-terminated string. Inputs that already end with a single NUL byte,
+/// such as [`CStr`](std::ffi::CStr) or `"nop\0"`, are passed as they are, owned strings and
+/// vectors are terminated in place, and the other inputs are copied once. Inputs containing a
+/// NUL byte before their end are rejected with [`MiscError::InteriorNul`].
+///
+/// A blanket implementation for `&T` where `T: AsRef<str>` would conflict with the ones for bytes
+/// and C strings, so the standard string types (`str`, `String`, `Box<str>`, `Rc<str>`,
+/// `Arc<str>`, `Cow<str>` and `&str`) are implemented one by one instead.
+pub trait AsmInput<'a> {
+    /// Converts the input into a NUL-terminated string.
+    fn into_cstr(self) -> Result<std::borrow::Cow<'a, std::ffi::CStr>>;
+}
+
+/// Converts borrowed bytes into a C string, without copying them if they are NUL-terminated.
+fn bytes_to_cstr(bytes: &[u8]) -> Result<std::borrow::Cow<'_, std::ffi::CStr>> {
+    match std::ffi::CStr::from_bytes_with_nul(bytes) {
+        Ok(cstr) => Ok(std::borrow::Cow::Borrowed(cstr)),
+        Err(_) => vec_to_cstr(bytes.to_vec()),
+    }
+}
+
+/// Converts owned bytes into a C string, reusing their allocation.
+fn vec_to_cstr(bytes: Vec<u8>) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+    let cstring = match std::ffi::CString::from_vec_with_nul(bytes) {
+        Ok(cstring) => cstring,
+        Err(e) => std::ffi::CString::new(e.into_bytes())
+            .map_err(|e| MiscError::InteriorNul(e.nul_position()))?,
+    };
+    Ok(std::borrow::Cow::Owned(cstring))
+}
+
+/// Implements [`AsmInput`] for references to string types, which are borrowed when possible.
+macro_rules! asm_input_str_refs {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl<'a> AsmInput<'a> for &'a $ty {
+                fn into_cstr(self) -> Result<std::borrow::Cow<'a, std::ffi::CStr>> {
+                    bytes_to_cstr(AsRef::<str>::as_ref(self).as_bytes())
+                }
+            }
+        )*
+    };
+}
+
+asm_input_str_refs!(
+    str,
+    String,
+    Box<str>,
+    std::rc::Rc<str>,
+    std::sync::Arc<str>,
+    std::borrow::Cow<'_, str>,
+    &str,
+);
+
+impl AsmInput<'static> for String {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+        vec_to_cstr(self.into_bytes())
+    }
+}
+
+impl AsmInput<'static> for Box<str> {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+        vec_to_cstr(self.into_string().into_bytes())
+    }
+}
+
+impl AsmInput<'static> for std::rc::Rc<str> {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+        vec_to_cstr(self.as_bytes().to_vec())
+    }
+}
+
+impl AsmInput<'static> for std::sync::Arc<str> {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+        vec_to_cstr(self.as_bytes().to_vec())
+    }
+}
+
+impl<'a> AsmInput<'a> for std::borrow::Cow<'a, str> {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'a, std::ffi::CStr>> {
+        match self {
+            std::borrow::Cow::Borrowed(s) => s.into_cstr(),
+            std::borrow::Cow::Owned(s) => s.into_cstr(),
+        }
+    }
+}
+
+impl<'a> AsmInput<'a> for &'a [u8] {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'a, std::ffi::CStr>> {
+        bytes_to_cstr(self)
+    }
+}
+
+impl AsmInput<'static> for Vec<u8> {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+        vec_to_cstr(self)
+    }
+}
+
+impl<'a> AsmInput<'a> for &'a std::ffi::CStr {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'a, std::ffi::CStr>> {
+        Ok(std::borrow::Cow::Borrowed(self))
+    }
+}
+
+impl<'a> AsmInput<'a> for &'a std::ffi::CString {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'a, std::ffi::CStr>> {
+        Ok(std::borrow::Cow::Borrowed(self.as_c_str()))
+    }
+}
+
+impl AsmInput<'static> for std::ffi::CString {
+    fn into_cstr(self) -> Result<std::borrow::Cow<'static, std::ffi::CStr>> {
+        Ok(std::borrow::Cow::Owned(self))
+    }
+}
+
 /// Output object created after assembling instructions.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct KeystoneOutput {
@@ -146,43 +387,156 @@ impl std::fmt::Display for KeystoneOutput {
     }
 }
 
+/// Encoded instructions, stored in the buffer allocated by Keystone and freed on drop.
+///
+/// Returned by [`Keystone::asm_buffer`], which avoids copying the output of `ks_asm`.
+#[derive(Debug)]
+pub struct KsBuffer {
+    /// Buffer allocated by `ks_asm`, which can be NULL if the output is empty.
+    ptr: *mut c_uchar,
+    /// Size of the buffer.
+    len: usize,
+    /// Number of statements that were encoded.
+    stat_count: usize,
+}
+
+// The buffer is owned and never modified once returned by Keystone.
+unsafe impl Send for KsBuffer {}
+unsafe impl Sync for KsBuffer {}
+
+impl KsBuffer {
+    /// Returns the number of statements that were encoded.
+    pub fn stat_count(&self) -> usize {
+        self.stat_count
+    }
+}
+
+impl std::ops::Deref for KsBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+}
+
+impl AsRef<[u8]> for KsBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for KsBuffer {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { ffi::ks_free(self.ptr) };
+        }
+    }
+}
+
 /// Reprensents a Keystone instance.
 #[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Keystone {
     /// Handle to the keystone instance.
     ks: ffi::KsHandle,
+    /// Architecture the instance was created for.
+    arch: ffi::Arch,
+    /// Hardware mode the instance was created for.
+    mode: ffi::Mode,
 }
 
 impl Keystone {
     /// Creates a new Keystone object.
     pub fn new(arch: ffi::Arch, mode: ffi::Mode) -> Result<Self> {
-        // Check if the version returned by the library matches with the bindings'.
-        if Self::version() != (ffi::API_MAJOR, ffi::API_MINOR) {
+        Self::load()?;
+        // Check if the version returned by the library is supported by the bindings.
+        if !version::Version::library().is_supported() {
             return Err(ffi::Error::VERSION)?;
         }
+        // Check if the architecture has been compiled into the library.
+        if !Self::arch_supported(arch) {
+            return Err(ffi::Error::ARCH)?;
+        }
         // Opens the Keystone instance.
         let mut ks = None;
-        let err = unsafe { ffi::ks_open(arch, mode, &mut ks) };
+        let err = ffi::Error::from_code(unsafe { ffi::ks_open(arch, mode, &mut ks) });
         if err == ffi::Error::OK {
             Ok(Keystone {
                 ks: ks.expect("Got NULL engine from ks_open()"),
+                arch,
+                mode,
             })
         } else {
             Err(err)?
         }
     }
 
+    /// Loads the library if it's resolved at runtime, which is a no-op otherwise.
+    pub(crate) fn load() -> Result<()> {
+        #[cfg(feature = "dynamic-load")]
+        match ffi::load_default() {
+            Ok(()) => {}
+            Err(ffi::LoadError::Version(..)) => return Err(ffi::Error::VERSION)?,
+            Err(_) => return Err(MiscError::Library)?,
+        }
+        Ok(())
+    }
+
     // Returns the major and minor version numbers from the library.
+    //
+    // With the `dynamic-load` feature, `(0, 0)` is returned if the library could not be loaded.
     pub fn version() -> (u32, u32) {
+        if Self::load().is_err() {
+            return (0, 0);
+        }
         let mut major = 0;
         let mut minor = 0;
         unsafe { ffi::ks_version(&mut major, &mut minor) };
         (major, minor)
     }
 
+    /// Returns `true` if `arch` has been compiled into the library.
+    ///
+    /// When building Keystone from source, only the architectures selected through the `arch-*`
+    /// features are available (all of them if none is selected).
+    ///
+    /// With the `dynamic-load` feature, `false` is returned if the library could not be loaded.
+    ///
+    /// See [`version::Capabilities`] to query all the features of the library at once.
+    pub fn arch_supported(arch: ffi::Arch) -> bool {
+        if Self::load().is_err() {
+            return false;
+        }
+        unsafe { ffi::ks_arch_supported(arch) != 0 }
+    }
+
+    /// Returns the architecture of the Keystone instance.
+    pub fn arch(&self) -> ffi::Arch {
+        self.arch
+    }
+
+    /// Returns the hardware mode of the Keystone instance.
+    pub fn mode(&self) -> ffi::Mode {
+        self.mode
+    }
+
     /// Sets an option of the Keystone engine after the instance has been created.
+    ///
+    /// Returns [`MiscError::InvalidConfig`] when setting a symbol resolver, since Keystone would
+    /// call the value as a function. Use [`Keystone::asm_with_resolver`] instead.
     pub fn option(&self, opt_type: ffi::OptionType, value: ffi::OptionValue) -> Result<()> {
-        let err = unsafe { ffi::ks_option(self.ks, opt_type, value) };
+        if opt_type == ffi::OptionType::SYM_RESOLVER && !value.is_empty() {
+            return Err(MiscError::InvalidConfig)?;
+        }
+        self.set_option(opt_type, value)
+    }
+
+    /// Sets an option of the Keystone engine, without checking its value.
+    fn set_option(&self, opt_type: ffi::OptionType, value: ffi::OptionValue) -> Result<()> {
+        let err = ffi::Error::from_code(unsafe { ffi::ks_option(self.ks, opt_type, value) });
         if err == ffi::Error::OK {
             Ok(())
         } else {
@@ -195,8 +549,62 @@ impl Keystone {
     /// The resulting machine code depends on the input buffer, its size, a base address and the
     /// number of instructions to encode. The method returns a [`KeystoneOutput`] object that
     /// contains the encoded instructions.
-    pub fn asm(&self, insns: String, address: u64) -> Result<KeystoneOutput> {
-        let insns_cstr = std::ffi::CString::new(insns).unwrap();
+    ///
+    /// `insns` can be a string, a C string or bytes (see [`AsmInput`]).
+    ///
+    /// Returns [`MiscError::InteriorNul`] if `insns` contains a NUL byte before its end, and
+    /// [`MiscError::OutputTooLarge`] if the size of the output doesn't fit in a `u32`.
+    pub fn asm<'a>(&self, insns: impl AsmInput<'a>, address: u64) -> Result<KeystoneOutput> {
+        let buffer = self.asm_buffer(insns, address)?;
+        let (Ok(size), Ok(stat_count)) = (buffer.len().try_into(), buffer.stat_count().try_into())
+        else {
+            return Err(MiscError::OutputTooLarge)?;
+        };
+        Ok(KeystoneOutput {
+            size,
+            stat_count,
+            bytes: buffer.to_vec(),
+        })
+    }
+
+    /// Assembles a program read from `reader`, e.g. a large source file.
+    ///
+    /// The input is read into a single buffer that is then terminated in place, so that it isn't
+    /// copied again before being passed to Keystone. Returns [`MiscError::Io`] if reading fails,
+    /// which only keeps the kind of the I/O error.
+    pub fn asm_reader(
+        &self,
+        mut reader: impl std::io::Read,
+        address: u64,
+    ) -> Result<KeystoneOutput> {
+        let mut insns = Vec::new();
+        reader
+            .read_to_end(&mut insns)
+            .map_err(|e| MiscError::Io(e.kind()))?;
+        self.asm(insns, address)
+    }
+
+    /// Assembles a program and appends the encoded instructions to `output`, returning the
+    /// number of statements that were encoded.
+    ///
+    /// Unlike [`Keystone::asm`], no intermediate vector is allocated, so that `output` can be
+    /// reused across calls.
+    pub fn asm_into<'a>(
+        &self,
+        insns: impl AsmInput<'a>,
+        address: u64,
+        output: &mut Vec<u8>,
+    ) -> Result<usize> {
+        let buffer = self.asm_buffer(insns, address)?;
+        output.extend_from_slice(&buffer);
+        Ok(buffer.stat_count())
+    }
+
+    /// Assembles a program and returns the buffer allocated by Keystone, without copying it.
+    ///
+    /// Returns [`MiscError::InteriorNul`] if `insns` contains a NUL byte before its end.
+    pub fn asm_buffer<'a>(&self, insns: impl AsmInput<'a>, address: u64) -> Result<KsBuffer> {
+        let insns_cstr = insns.into_cstr()?;
         let mut encoding: *mut c_uchar = std::ptr::null_mut();
         let mut encoding_size: size_t = 0;
         let mut stat_count: size_t = 0;
@@ -212,15 +620,11 @@ impl Keystone {
             )
         };
         if err == 0 {
-            // Converting the output machine code to a Vec<u8>.
-            let insns_slice = unsafe { std::slice::from_raw_parts(encoding, encoding_size) };
-            let insns = insns_slice.to_vec();
-            // Freeing memory allocated by `ks_asm`.
-            unsafe { ffi::ks_free(encoding) };
-            Ok(KeystoneOutput {
-                size: encoding_size.try_into().expect("size_t overflowed u32"),
-                stat_count: stat_count.try_into().expect("size_t overflowed u32"),
-                bytes: insns,
+            // The buffer allocated by `ks_asm` is freed when the `KsBuffer` is dropped.
+            Ok(KsBuffer {
+                ptr: encoding,
+                len: encoding_size,
+                stat_count,
             })
         } else {
             // If an error occured after calling ks_asm, check if an strerrno has been set and
@@ -231,6 +635,44 @@ impl Keystone {
             }
         }
     }
+
+    /// Assembles a program and returns its listing, which shows the address and encoding of
+    /// every source line (see [`listing::Listing`]).
+    pub fn listing(&self, insns: &str, address: u64) -> Result<listing::Listing> {
+        listing::Listing::assemble(self, insns, address)
+    }
+
+    /// Assembles every statement of a program, returning the errors of all those that failed
+    /// along with the encoding of the others (see [`diagnostics::CheckAssembler`]).
+    pub fn asm_all(&self, insns: &str, address: u64) -> diagnostics::Report {
+        diagnostics::CheckAssembler::new(self, address).assemble(insns)
+    }
+
+    /// Assembles a program, resolving the symbols it doesn't define with `resolver`.
+    ///
+    /// The resolver is called by Keystone for every symbol missing from `insns` and returns its
+    /// value, or `None` if the symbol is unknown. If the resolver panics, the panic is caught and
+    /// [`MiscError::ResolverPanic`] is returned once Keystone has returned.
+    pub fn asm_with_resolver<'a, F>(
+        &self,
+        insns: impl AsmInput<'a>,
+        address: u64,
+        mut resolver: F,
+    ) -> Result<KeystoneOutput>
+    where
+        F: FnMut(&str) -> Option<u64>,
+    {
+        let resolver: &mut Resolver<'_> = &mut resolver;
+        // The lifetime of the resolver is erased to store it in the thread-local variable. This
+        // is fine because the guard below removes it before `resolver` goes out of scope.
+        let resolver: *mut Resolver<'static> = unsafe { std::mem::transmute(resolver) };
+        let _guard = ResolverGuard::install(self, resolver)?;
+        let res = self.asm(insns, address);
+        if RESOLVER_PANIC.with(|p| p.take()).is_some() {
+            return Err(MiscError::ResolverPanic)?;
+        }
+        res
+    }
 }
 
 impl Drop for Keystone {
@@ -239,6 +681,90 @@ impl Drop for Keystone {
     }
 }
 
+// -----------------------------------------------------------------------------------------------
+// Symbol resolver
+// -----------------------------------------------------------------------------------------------
+
+/// Closure resolving a symbol name into its value.
+type Resolver<'a> = dyn FnMut(&str) -> Option<u64> + 'a;
+
+thread_local! {
+    /// Resolver of the `ks_asm` call currently running on this thread, if any.
+    static RESOLVER: std::cell::Cell<Option<*mut Resolver<'static>>> =
+        const { std::cell::Cell::new(None) };
+    /// Payload of a panic that occured in the current resolver, if any.
+    static RESOLVER_PANIC: std::cell::Cell<Option<Box<dyn std::any::Any + Send>>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Symbol resolver registered to Keystone, forwarding calls to the current thread's resolver.
+extern "C" fn resolver_trampoline(symbol: *const c_char, value: *mut u64) -> bool {
+    let resolver = match RESOLVER.with(|r| r.get()) {
+        Some(resolver) => resolver,
+        None => return false,
+    };
+    // Stop resolving symbols once the resolver has panicked.
+    let panicked = RESOLVER_PANIC.with(|p| {
+        let payload = p.take();
+        let panicked = payload.is_some();
+        p.set(payload);
+        panicked
+    });
+    if panicked {
+        return false;
+    }
+    let symbol = unsafe { std::ffi::CStr::from_ptr(symbol) }.to_string_lossy();
+    // Unwinding through Keystone is undefined behavior, panics are caught here and reported
+    // once `ks_asm` has returned.
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+        (*resolver)(&symbol)
+    }));
+    match res {
+        Ok(Some(v)) => {
+            unsafe { *value = v };
+            true
+        }
+        Ok(None) => false,
+        Err(payload) => {
+            RESOLVER_PANIC.with(|p| p.set(Some(payload)));
+            false
+        }
+    }
+}
+
+/// Registers a resolver for the lifetime of the guard.
+struct ResolverGuard<'a> {
+    /// Instance the resolver is registered to.
+    engine: &'a Keystone,
+    /// Resolver that was registered before this one, restored on drop.
+    prev: Option<*mut Resolver<'static>>,
+}
+
+impl<'a> ResolverGuard<'a> {
+    /// Registers `resolver` to the current thread and to `engine`.
+    fn install(engine: &'a Keystone, resolver: *mut Resolver<'static>) -> Result<Self> {
+        let prev = RESOLVER.with(|r| r.replace(Some(resolver)));
+        let guard = ResolverGuard { engine, prev };
+        let trampoline: ffi::SymResolver = resolver_trampoline;
+        let value = unsafe { ffi::OptionValue::from_bits_unchecked(trampoline as size_t) };
+        engine.set_option(ffi::OptionType::SYM_RESOLVER, value)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for ResolverGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::ks_option(
+                self.engine.ks,
+                ffi::OptionType::SYM_RESOLVER,
+                ffi::OptionValue::empty(),
+            )
+        };
+        RESOLVER.with(|r| r.set(self.prev));
+    }
+}
+
 // -----------------------------------------------------------------------------------------------
 // Tests
 // -----------------------------------------------------------------------------------------------
@@ -258,7 +784,7 @@ mod tests {
             .option(OptionType::SYNTAX, OptionValue::SYNTAX_NASM)
             .expect("Could not set option to nasm syntax");
         // Assemble instructions
-        let output_res = engine.asm("mov ah, 0x80".to_string(), 0);
+        let output_res = engine.asm("mov ah, 0x80", 0);
         assert!(output_res.is_ok());
         // Make sure the output object is sane.
         let output = output_res.unwrap();
@@ -267,8 +793,77 @@ mod tests {
         assert_eq!(output.stat_count, 1);
         // Ensure an error is returned when invalid instructions are provided.
         assert_eq!(
-            engine.asm("INVALID".to_string(), 0),
+            engine.asm("INVALID", 0),
             Err(KeystoneError::Engine(ffi::Error::ASM_MNEMONICFAIL))
         );
     }
+
+    #[test]
+    fn test_buffer() {
+        let engine = Keystone::new(Arch::X86, Mode::MODE_32).unwrap();
+        let buffer = engine.asm_buffer("nop; ret".to_string(), 0).unwrap();
+        assert_eq!(&*buffer, &[0x90, 0xc3]);
+        assert_eq!(buffer.stat_count(), 2);
+        let mut output = vec![0xcc];
+        assert_eq!(engine.asm_into("nop".to_string(), 0, &mut output), Ok(1));
+        assert_eq!(engine.asm_into("ret".to_string(), 0, &mut output), Ok(1));
+        assert_eq!(output, vec![0xcc, 0x90, 0xc3]);
+        assert!(engine
+            .asm_into("INVALID".to_string(), 0, &mut output)
+            .is_err());
+        assert_eq!(output.len(), 3);
+    }
+
+    #[test]
+    fn test_misc_errors() {
+        let engine = Keystone::new(Arch::X86, Mode::MODE_32).unwrap();
+        assert_eq!(
+            engine.asm("nop\0nop".to_string(), 0),
+            Err(MiscError::InteriorNul(3).into())
+        );
+        assert_eq!(
+            engine.asm_with_resolver("jmp target".to_string(), 0, |_| panic!("resolver")),
+            Err(MiscError::ResolverPanic.into())
+        );
+        let value = unsafe { OptionValue::from_bits_unchecked(0x1000) };
+        assert_eq!(
+            engine.option(OptionType::SYM_RESOLVER, value),
+            Err(MiscError::InvalidConfig.into())
+        );
+    }
+
+    #[test]
+    fn test_inputs() {
+        use std::borrow::Cow;
+
+        let cstr = c"nop";
+        assert!(matches!("nop\0".into_cstr(), Ok(Cow::Borrowed(s)) if s == cstr));
+        assert!(matches!(b"nop\0".as_slice().into_cstr(), Ok(Cow::Borrowed(s)) if s == cstr));
+        assert!(matches!(cstr.into_cstr(), Ok(Cow::Borrowed(s)) if s == cstr));
+        assert!(matches!("nop".into_cstr(), Ok(Cow::Owned(s)) if s.as_c_str() == cstr));
+        assert!(matches!(b"nop".to_vec().into_cstr(), Ok(Cow::Owned(s)) if s.as_c_str() == cstr));
+        assert!(matches!((&"nop\0").into_cstr(), Ok(Cow::Borrowed(s)) if s == cstr));
+        assert!(matches!(Cow::from("nop\0").into_cstr(), Ok(Cow::Borrowed(s)) if s == cstr));
+        let boxed: Box<str> = "nop".into();
+        assert!(matches!(boxed.into_cstr(), Ok(Cow::Owned(s)) if s.as_c_str() == cstr));
+        let shared: std::sync::Arc<str> = "nop\0".into();
+        assert!(matches!((&shared).into_cstr(), Ok(Cow::Borrowed(s)) if s == cstr));
+        assert_eq!(
+            "nop\0nop\0".into_cstr(),
+            Err(MiscError::InteriorNul(3).into())
+        );
+        assert_eq!(
+            "nop\0nop".to_string().into_cstr(),
+            Err(MiscError::InteriorNul(3).into())
+        );
+
+        let engine = Keystone::new(Arch::X86, Mode::MODE_32).unwrap();
+        let expected = engine.asm("nop; ret", 0).unwrap();
+        assert_eq!(engine.asm(cstr, 0).unwrap().bytes, vec![0x90]);
+        assert_eq!(
+            engine.asm(b"nop; ret\0".as_slice(), 0),
+            Ok(expected.clone())
+        );
+        assert_eq!(engine.asm_reader(&b"nop; ret"[..], 0), Ok(expected));
+    }
 }
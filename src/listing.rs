@@ -0,0 +1,232 @@
+//! Assembly listings.
+//!
+//! A [`Listing`] shows every line of the source next to its address and encoding, in the style
+//! of the listings produced by GNU as with `-al`. Labels and comments are preserved since lines
+//! are reproduced verbatim. Listings can be rendered as text, through [`std::fmt::Display`], or
+//! as an HTML table with [`Listing::to_html`].
+//!
+//! ```no_run
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let listing = engine
+//!     .listing("start: mov eax, 1 # one\njmp start", 0x1000)
+//!     .expect("Could not assemble");
+//! print!("{}", listing);
+//! ```
+
+use crate::layout;
+use crate::{Keystone, Result};
+
+/// Maximum number of bytes shown on a line of the listing.
+const BYTES_PER_LINE: usize = 8;
+
+/// Line of a listing.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct ListingLine {
+    /// Line number in the source, starting at 1.
+    pub line: usize,
+    /// Address of the first statement of the line, if it has one.
+    pub address: Option<u64>,
+    /// Encoding of the statements of the line. The bytes skipped by `.org` aren't included.
+    pub bytes: Vec<u8>,
+    /// Source line, as written.
+    pub source: String,
+}
+
+/// Listing of an assembly program.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Listing {
+    /// Lines of the listing, one per source line.
+    pub lines: Vec<ListingLine>,
+}
+
+impl Listing {
+    /// Assembles `insns` at `address` and returns its listing.
+    ///
+    /// Returns the error of the first statement that could not be assembled, if any.
+    pub fn assemble(engine: &Keystone, insns: &str, address: u64) -> Result<Self> {
+        let mut lines: Vec<ListingLine> = insns
+            .lines()
+            .enumerate()
+            .map(|(idx, source)| ListingLine {
+                line: idx + 1,
+                address: None,
+                bytes: vec![],
+                source: source.to_string(),
+            })
+            .collect();
+        let stmts = layout::parse(engine.arch(), insns);
+        let lay = layout::layout(engine, &stmts, address, |_| None);
+        for (stmt, placed) in stmts.iter().zip(lay.placed) {
+            let bytes = placed.bytes?;
+            let line = &mut lines[stmt.line - 1];
+            if stmt.kind != layout::StatementKind::Empty && line.address.is_none() {
+                line.address = Some(placed.address);
+            }
+            line.bytes.extend(bytes);
+        }
+        Ok(Listing { lines })
+    }
+
+    /// Returns the number of hexadecimal digits needed to show the addresses of the listing.
+    fn address_width(&self) -> usize {
+        let max = self
+            .lines
+            .iter()
+            .filter_map(|l| l.address)
+            .max()
+            .unwrap_or(0);
+        match max {
+            0..=0xffff => 4,
+            0x10000..=0xffff_ffff => 8,
+            _ => 16,
+        }
+    }
+
+    /// Renders the listing as an HTML table.
+    ///
+    /// Cells have the `line`, `address`, `bytes` and `source` classes, for styling.
+    pub fn to_html(&self) -> String {
+        let width = self.address_width();
+        let mut out = String::from("<table class=\"listing\">\n");
+        for line in &self.lines {
+            let address = line
+                .address
+                .map(|a| format!("{:0width$X}", a, width = width))
+                .unwrap_or_default();
+            out.push_str(&format!(
+                "<tr><td class=\"line\">{}</td><td class=\"address\">{}</td>\
+                 <td class=\"bytes\">{}</td><td class=\"source\">{}</td></tr>\n",
+                line.line,
+                address,
+                hex(&line.bytes),
+                escape_html(&line.source)
+            ));
+        }
+        out.push_str("</table>\n");
+        out
+    }
+}
+
+impl std::fmt::Display for Listing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.address_width();
+        let bytes_width = BYTES_PER_LINE * 2;
+        for line in &self.lines {
+            let mut chunks = line.bytes.chunks(BYTES_PER_LINE);
+            let address = line
+                .address
+                .map(|a| format!("{:0width$X}", a, width = width))
+                .unwrap_or_else(|| " ".repeat(width));
+            let first = chunks.next().map(hex).unwrap_or_default();
+            writeln!(
+                f,
+                "{:>4} {} {:<bytes_width$} {}",
+                line.line,
+                address,
+                first,
+                line.source,
+                bytes_width = bytes_width
+            )?;
+            // Encodings that don't fit on the line continue on the following ones.
+            for (idx, chunk) in chunks.enumerate() {
+                let address = line.address.unwrap_or(0) + ((idx + 1) * BYTES_PER_LINE) as u64;
+                writeln!(
+                    f,
+                    "{:>4} {:0width$X} {}",
+                    line.line,
+                    address,
+                    hex(chunk),
+                    width = width
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats bytes as uppercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Escapes the characters of `s` that are special in HTML.
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi;
+
+    fn listing() -> Listing {
+        Listing {
+            lines: vec![
+                ListingLine {
+                    line: 1,
+                    address: None,
+                    bytes: vec![],
+                    source: "# <entry>".to_string(),
+                },
+                ListingLine {
+                    line: 2,
+                    address: Some(0x1000),
+                    bytes: vec![0xb8, 0x01, 0x00, 0x00, 0x00],
+                    source: "start: mov eax, 1".to_string(),
+                },
+                ListingLine {
+                    line: 3,
+                    address: Some(0x1005),
+                    bytes: (0..10).collect(),
+                    source: ".byte 0, 1, 2, 3, 4, 5, 6, 7, 8, 9".to_string(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            listing().to_string(),
+            "   1                       # <entry>\n\
+             \x20  2 1000 B801000000       start: mov eax, 1\n\
+             \x20  3 1005 0001020304050607 .byte 0, 1, 2, 3, 4, 5, 6, 7, 8, 9\n\
+             \x20  3 100D 0809\n"
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        let html = listing().to_html();
+        assert!(html.contains("<td class=\"source\"># &lt;entry&gt;</td>"));
+        assert!(html.contains("<td class=\"address\">1000</td><td class=\"bytes\">B801000000</td>"));
+    }
+
+    #[test]
+    fn test_assemble() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let listing = Listing::assemble(&engine, "start:\n  nop; nop # two\njmp start", 0).unwrap();
+        assert_eq!(listing.lines[0].address, None);
+        assert_eq!(listing.lines[1].address, Some(0));
+        assert_eq!(listing.lines[1].bytes, vec![0x90, 0x90]);
+        assert_eq!(listing.lines[1].source, "  nop; nop # two");
+        assert_eq!(listing.lines[2].address, Some(2));
+        assert_eq!(listing.lines[2].bytes, vec![0xeb, 0xfc]);
+    }
+}
@@ -0,0 +1,1198 @@
+//! Assembly preprocessor.
+//!
+//! [`Preprocessor`] runs before Keystone and handles:
+//!
+//!  * `%include`, `#include` and `.include`, searching the directory of the including file, then
+//!    the directories given with [`Preprocessor::include_dir`];
+//!  * `#define`/`%define` constants and parameterized macros (`#define ADD(a, b) add a, b`), and
+//!    `#undef`/`%undef`;
+//!  * multi-line macros, between `%macro NAME(a, b)` and `%endmacro`;
+//!  * conditional blocks with `#if`, `#ifdef`, `#ifndef`, `#elif`, `#else` and `#endif`, or the
+//!    same directives prefixed with `%`;
+//!  * `.incbin "path"[, skip[, count]]`, which Keystone doesn't support, expanded into `.byte`
+//!    directives so that the labels that follow it are placed correctly, including when it comes
+//!    from a macro. Binary files are only read from the include directories;
+//!  * `#error`, which stops preprocessing with a message.
+//!
+//! Included and binary files must resolve inside of the directory they are searched in, once
+//! symbolic links and `..` are resolved, so absolute paths are rejected. Directives can follow a
+//! label, which is kept in the output.
+//!
+//! Only the directives above are interpreted, other lines starting with `#` or `%` are kept as
+//! they are, so comments and AT&T registers are left untouched. Macros are expanded everywhere
+//! except in string literals.
+//!
+//! The [`Preprocessed`] source keeps track of the file and line every output line comes from, so
+//! that errors can be reported against the original sources.
+//!
+//! ```no_run
+//! use keystone_engine::preprocess::Preprocessor;
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let source = Preprocessor::new()
+//!     .include_dir("include")
+//!     .define("STACK_SIZE", "0x100")
+//!     .process("%include \"macros.inc\"\nsub esp, STACK_SIZE")
+//!     .expect("Could not preprocess");
+//! match source.assemble(&engine, 0) {
+//!     Ok(output) => println!("{}", output),
+//!     Err(err) => println!("{}", err),
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::layout;
+use crate::{Keystone, KeystoneError, KeystoneOutput};
+
+/// Maximum nesting of includes and macro expansions.
+const MAX_DEPTH: usize = 64;
+
+/// Number of bytes per data directive emitted for `.incbin`.
+const INCBIN_BYTES_PER_LINE: usize = 16;
+
+// -----------------------------------------------------------------------------------------------
+// Errors
+// -----------------------------------------------------------------------------------------------
+
+/// Location of a line in the original sources.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct SourceLocation {
+    /// File containing the line, or `None` for the source given to [`Preprocessor::process`].
+    pub file: Option<PathBuf>,
+    /// Line number in the file, starting at 1.
+    pub line: usize,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file.display(), self.line),
+            None => write!(f, "<input>:{}", self.line),
+        }
+    }
+}
+
+/// Kinds of preprocessing errors.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum PreprocessErrorKind {
+    /// Included file could not be found.
+    IncludeNotFound(String),
+    /// Included file could not be read.
+    Io(PathBuf),
+    /// Conditional directive without a matching `#if`, or `#if` without a matching `#endif`.
+    Conditional,
+    /// Invalid `#if` or `#elif` expression.
+    Expression,
+    /// Malformed directive.
+    Directive,
+    /// Parameterized macro called with the wrong number of arguments.
+    MacroArguments(String),
+    /// Includes or macro expansions nested too deeply, usually because they are recursive.
+    Recursion,
+    /// Included or binary file resolving outside of the directories it is searched in.
+    Sandbox(String),
+    /// Error raised by an `#error` directive.
+    User(String),
+}
+
+impl std::fmt::Display for PreprocessErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreprocessErrorKind::IncludeNotFound(name) => write!(f, "file not found: {}", name),
+            PreprocessErrorKind::Io(path) => write!(f, "could not read {}", path.display()),
+            PreprocessErrorKind::Conditional => write!(f, "unbalanced conditional directive"),
+            PreprocessErrorKind::Expression => write!(f, "invalid expression"),
+            PreprocessErrorKind::Directive => write!(f, "malformed directive"),
+            PreprocessErrorKind::MacroArguments(name) => {
+                write!(f, "wrong number of arguments for macro {}", name)
+            }
+            PreprocessErrorKind::Recursion => write!(f, "includes or macros nested too deeply"),
+            PreprocessErrorKind::Sandbox(name) => {
+                write!(f, "file outside of the search directories: {}", name)
+            }
+            PreprocessErrorKind::User(msg) => write!(f, "#error {}", msg),
+        }
+    }
+}
+
+/// Preprocessing error, along with the line it occured at.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct PreprocessError {
+    /// Kind of the error.
+    pub kind: PreprocessErrorKind,
+    /// Line of the directive or macro call that caused the error.
+    pub location: SourceLocation,
+}
+
+impl std::error::Error for PreprocessError {}
+
+impl std::fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+/// Assembly error, along with the line of the original sources it occured at.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct LocatedError {
+    /// Error returned by Keystone.
+    pub error: KeystoneError,
+    /// Line of the statement that failed, if it could be found.
+    pub location: Option<SourceLocation>,
+}
+
+impl std::error::Error for LocatedError {}
+
+impl std::fmt::Display for LocatedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Output
+// -----------------------------------------------------------------------------------------------
+
+/// Preprocessed source.
+#[derive(Clone, Default, Eq, PartialEq, Debug)]
+pub struct Preprocessed {
+    /// Source to assemble.
+    pub source: String,
+    /// Origin of every line of `source`.
+    pub lines: Vec<SourceLocation>,
+}
+
+impl Preprocessed {
+    /// Returns the origin of the line `line` of the preprocessed source, starting at 1.
+    pub fn location(&self, line: usize) -> Option<&SourceLocation> {
+        self.lines.get(line.checked_sub(1)?)
+    }
+
+    /// Assembles the preprocessed source at `address`.
+    ///
+    /// On failure, the statements are assembled one by one to find the line that caused the
+    /// error, which is then mapped back to the original sources.
+    pub fn assemble(
+        &self,
+        engine: &Keystone,
+        address: u64,
+    ) -> std::result::Result<KeystoneOutput, LocatedError> {
+        engine.asm(self.source.clone(), address).map_err(|error| {
+            let stmts = layout::parse(engine.arch(), &self.source);
+            let lay = layout::layout(engine, &stmts, address, |_| None);
+            let location = stmts
+                .iter()
+                .zip(&lay.placed)
+                .find(|(_, p)| p.bytes.is_err())
+                .and_then(|(s, _)| self.location(s.line).cloned());
+            LocatedError { error, location }
+        })
+    }
+
+    /// Appends `text`, which comes from `location`, to the source.
+    fn push(&mut self, text: &str, location: &SourceLocation) {
+        // Empty lines are kept, so that the output mirrors the input.
+        let text = if text.is_empty() { "\n" } else { text };
+        for line in text.lines() {
+            self.source.push_str(line);
+            self.source.push('\n');
+            self.lines.push(location.clone());
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Preprocessor
+// -----------------------------------------------------------------------------------------------
+
+/// Macro definition.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct Macro {
+    /// Names of the parameters, or `None` for constants.
+    params: Option<Vec<String>>,
+    /// Replacement text, which may span several lines.
+    body: String,
+}
+
+/// State of a conditional block.
+#[derive(Clone, Debug)]
+struct Conditional {
+    /// Whether the block containing the conditional is active.
+    parent: bool,
+    /// Whether the current branch is active.
+    active: bool,
+    /// Whether a branch has been taken already.
+    taken: bool,
+    /// Whether `#else` has been seen.
+    has_else: bool,
+}
+
+/// Multi-line macro being recorded.
+#[derive(Debug)]
+struct Recording {
+    /// Name of the macro.
+    name: String,
+    /// Definition, whose body is filled as lines are read.
+    definition: Macro,
+    /// Location of the `%macro` directive.
+    location: SourceLocation,
+}
+
+/// Assembly preprocessor.
+#[derive(Clone, Default, Debug)]
+pub struct Preprocessor {
+    /// Directories searched by includes.
+    include_dirs: Vec<PathBuf>,
+    /// Macros defined before preprocessing.
+    macros: HashMap<String, Macro>,
+}
+
+impl Preprocessor {
+    /// Creates a new preprocessor without include directories or predefined macros.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a directory searched by includes, after the directory of the including file and
+    /// the directories added before it.
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Defines the constant `name`, as `#define name value` would.
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                params: None,
+                body: value.to_string(),
+            },
+        );
+        self
+    }
+
+    /// Preprocesses `source`. Relative includes are searched in the include directories only.
+    pub fn process(&self, source: &str) -> std::result::Result<Preprocessed, PreprocessError> {
+        let mut state = State::new(self);
+        state.run(source, None, 0)?;
+        Ok(state.output)
+    }
+
+    /// Preprocesses the file at `path`.
+    pub fn process_file(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> std::result::Result<Preprocessed, PreprocessError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|_| PreprocessError {
+            kind: PreprocessErrorKind::Io(path.to_path_buf()),
+            location: SourceLocation {
+                file: Some(path.to_path_buf()),
+                line: 0,
+            },
+        })?;
+        let mut state = State::new(self);
+        state.run(&source, Some(path), 0)?;
+        Ok(state.output)
+    }
+}
+
+/// State of a preprocessing run.
+struct State<'a> {
+    /// Configuration of the preprocessor.
+    config: &'a Preprocessor,
+    /// Macros currently defined.
+    macros: HashMap<String, Macro>,
+    /// Conditional blocks currently open.
+    conditionals: Vec<Conditional>,
+    /// Multi-line macro being recorded, if any.
+    recording: Option<Recording>,
+    /// Output of the preprocessor.
+    output: Preprocessed,
+}
+
+impl<'a> State<'a> {
+    /// Creates the initial state of a run.
+    fn new(config: &'a Preprocessor) -> Self {
+        State {
+            config,
+            macros: config.macros.clone(),
+            conditionals: vec![],
+            recording: None,
+            output: Preprocessed::default(),
+        }
+    }
+
+    /// Returns `true` if the lines being read are kept.
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    /// Preprocesses `source`, read from `file`, at include depth `depth`.
+    fn run(
+        &mut self,
+        source: &str,
+        file: Option<&Path>,
+        depth: usize,
+    ) -> std::result::Result<(), PreprocessError> {
+        let open = self.conditionals.len();
+        let mut lines = source.lines().enumerate();
+        let mut location = SourceLocation {
+            file: file.map(Path::to_path_buf),
+            line: 0,
+        };
+        let error = |kind, location: &SourceLocation| PreprocessError {
+            kind,
+            location: location.clone(),
+        };
+        while let Some((idx, line)) = lines.next() {
+            location.line = idx + 1;
+            let line = line.to_string();
+            if let Some(recording) = &mut self.recording {
+                if directive(&line).is_some_and(|(d, _)| d == "endmacro") {
+                    let recording = self.recording.take().unwrap();
+                    self.macros.insert(recording.name, recording.definition);
+                } else {
+                    recording.definition.body.push_str(&line);
+                    recording.definition.body.push('\n');
+                }
+                continue;
+            }
+            let (label, code) = split_label(&line);
+            let Some((name, _)) = directive(code) else {
+                if self.active() {
+                    self.expand(&line, &mut vec![], 0)
+                        .and_then(|text| self.push_expanded(&text, &location))
+                        .map_err(|kind| error(kind, &location))?;
+                }
+                continue;
+            };
+            let label = label.map(str::to_string);
+            let mut line = code.to_string();
+            if let (Some(label), true) = (&label, self.active()) {
+                self.output.push(&format!("{}:", label), &location);
+            }
+            // Directives continue on the next line when they end with a backslash.
+            while line.ends_with('\\') {
+                line.pop();
+                match lines.next() {
+                    Some((_, next)) => line.push_str(next),
+                    None => break,
+                }
+            }
+            let args = directive(&line).map_or("", |(_, args)| args).to_string();
+            if name == "include" && self.active() {
+                self.include(&args, &location, depth)?;
+                continue;
+            }
+            self.directive(name, &args, &location)
+                .map_err(|kind| error(kind, &location))?;
+        }
+        if let Some(recording) = &self.recording {
+            return Err(error(PreprocessErrorKind::Directive, &recording.location));
+        }
+        if self.conditionals.len() != open {
+            return Err(error(PreprocessErrorKind::Conditional, &location));
+        }
+        Ok(())
+    }
+
+    /// Handles the directive `name` with arguments `args`.
+    fn directive(
+        &mut self,
+        name: &str,
+        args: &str,
+        location: &SourceLocation,
+    ) -> std::result::Result<(), PreprocessErrorKind> {
+        let active = self.active();
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                let value = active && self.condition(name, args)?;
+                self.conditionals.push(Conditional {
+                    parent: active,
+                    active: value,
+                    taken: value,
+                    has_else: false,
+                });
+            }
+            "elif" => {
+                let cond = self.conditionals.last().cloned();
+                let cond = cond.filter(|c| !c.has_else);
+                let mut cond = cond.ok_or(PreprocessErrorKind::Conditional)?;
+                cond.active = cond.parent && !cond.taken && self.condition("if", args)?;
+                cond.taken |= cond.active;
+                *self.conditionals.last_mut().unwrap() = cond;
+            }
+            "else" => {
+                let cond = self.conditionals.last_mut();
+                let cond = cond.filter(|c| !c.has_else);
+                let cond = cond.ok_or(PreprocessErrorKind::Conditional)?;
+                cond.active = cond.parent && !cond.taken;
+                cond.taken = true;
+                cond.has_else = true;
+            }
+            "endif" => {
+                self.conditionals
+                    .pop()
+                    .ok_or(PreprocessErrorKind::Conditional)?;
+            }
+            _ if !active => {}
+            "define" | "macro" => {
+                let (ident, rest) = split_ident(args);
+                if !is_name(ident) {
+                    return Err(PreprocessErrorKind::Directive);
+                }
+                let (params, body) = match rest.strip_prefix('(') {
+                    Some(rest) => {
+                        let end = rest.find(')').ok_or(PreprocessErrorKind::Directive)?;
+                        let params: Vec<String> = rest[..end]
+                            .split(',')
+                            .map(|p| p.trim().to_string())
+                            .filter(|p| !p.is_empty())
+                            .collect();
+                        if !params.iter().all(|p| is_name(p)) {
+                            return Err(PreprocessErrorKind::Directive);
+                        }
+                        (Some(params), &rest[end + 1..])
+                    }
+                    None => (None, rest),
+                };
+                let definition = Macro {
+                    params,
+                    body: body.trim().to_string(),
+                };
+                if name == "define" {
+                    self.macros.insert(ident.to_string(), definition);
+                } else {
+                    self.recording = Some(Recording {
+                        name: ident.to_string(),
+                        definition: Macro {
+                            body: String::new(),
+                            ..definition
+                        },
+                        location: location.clone(),
+                    });
+                }
+            }
+            "undef" => {
+                self.macros.remove(args.trim());
+            }
+            "incbin" => self.incbin(args, location)?,
+            "error" => return Err(PreprocessErrorKind::User(args.trim().to_string())),
+            _ => return Err(PreprocessErrorKind::Directive),
+        }
+        Ok(())
+    }
+
+    /// Preprocesses the file included by `args`, on the line at `location`.
+    fn include(
+        &mut self,
+        args: &str,
+        location: &SourceLocation,
+        depth: usize,
+    ) -> std::result::Result<(), PreprocessError> {
+        let error = |kind| PreprocessError {
+            kind,
+            location: location.clone(),
+        };
+        if depth >= MAX_DEPTH {
+            return Err(error(PreprocessErrorKind::Recursion));
+        }
+        let args = args.trim();
+        let name = args
+            .strip_prefix('"')
+            .and_then(|a| a.strip_suffix('"'))
+            .or_else(|| args.strip_prefix('<').and_then(|a| a.strip_suffix('>')))
+            .ok_or_else(|| error(PreprocessErrorKind::Directive))?;
+        let parent = location.file.as_deref().and_then(Path::parent);
+        let dirs = parent
+            .into_iter()
+            .chain(self.config.include_dirs.iter().map(PathBuf::as_path));
+        let path = find_file(name, dirs).map_err(error)?;
+        let source = std::fs::read_to_string(&path)
+            .map_err(|_| error(PreprocessErrorKind::Io(path.clone())))?;
+        // Errors in the included file are reported at their own location.
+        self.run(&source, Some(&path), depth + 1)
+    }
+
+    /// Appends the expanded `text`, emulating the `.incbin` directives it contains.
+    fn push_expanded(
+        &mut self,
+        text: &str,
+        location: &SourceLocation,
+    ) -> std::result::Result<(), PreprocessErrorKind> {
+        // Empty lines are kept, so that the output mirrors the input.
+        let text = if text.is_empty() { "\n" } else { text };
+        for line in text.lines() {
+            let (label, code) = split_label(line);
+            match directive(code) {
+                Some(("incbin", args)) => {
+                    if let Some(label) = label {
+                        self.output.push(&format!("{}:", label), location);
+                    }
+                    self.incbin(args, location)?;
+                }
+                _ => self.output.push(line, location),
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands `.incbin "path"[, skip[, count]]` into data directives.
+    ///
+    /// Unlike includes, binary files are only searched in the include directories.
+    fn incbin(
+        &mut self,
+        args: &str,
+        location: &SourceLocation,
+    ) -> std::result::Result<(), PreprocessErrorKind> {
+        let args = self.expand(args.trim(), &mut vec![], 0)?;
+        let (name, rest) = args
+            .strip_prefix('"')
+            .and_then(|a| a.split_once('"'))
+            .ok_or(PreprocessErrorKind::Directive)?;
+        let mut numbers = rest.split(',').skip(1).map(|n| {
+            layout::parse_int(n)
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or(PreprocessErrorKind::Directive)
+        });
+        if !rest.trim().is_empty() && !rest.trim_start().starts_with(',') {
+            return Err(PreprocessErrorKind::Directive);
+        }
+        let skip = numbers.next().transpose()?.unwrap_or(0);
+        let count = numbers.next().transpose()?;
+        if numbers.next().is_some() {
+            return Err(PreprocessErrorKind::Directive);
+        }
+        let path = find_file(name, self.config.include_dirs.iter().map(PathBuf::as_path))?;
+        let data = std::fs::read(&path).map_err(|_| PreprocessErrorKind::Io(path.clone()))?;
+        let data = data.get(skip..).ok_or(PreprocessErrorKind::Directive)?;
+        let data = match count {
+            Some(count) => data.get(..count).ok_or(PreprocessErrorKind::Directive)?,
+            None => data,
+        };
+        for chunk in data.chunks(INCBIN_BYTES_PER_LINE) {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("0x{:02x}", b)).collect();
+            self.output
+                .push(&format!(".byte {}", bytes.join(", ")), location);
+        }
+        Ok(())
+    }
+
+    /// Evaluates the condition of an `#if`, `#ifdef` or `#ifndef` directive.
+    fn condition(&self, name: &str, args: &str) -> std::result::Result<bool, PreprocessErrorKind> {
+        match name {
+            "ifdef" => Ok(self.macros.contains_key(args.trim())),
+            "ifndef" => Ok(!self.macros.contains_key(args.trim())),
+            _ => {
+                let args = self.replace_defined(args)?;
+                let expr = self.expand(&args, &mut vec![], 0)?;
+                Ok(Expression::new(&expr).evaluate()? != 0)
+            }
+        }
+    }
+
+    /// Replaces `defined(NAME)` and `defined NAME` with 1 or 0.
+    fn replace_defined(&self, text: &str) -> std::result::Result<String, PreprocessErrorKind> {
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(pos) = find_ident(rest, "defined") {
+            out.push_str(&rest[..pos]);
+            let after = rest[pos + "defined".len()..].trim_start();
+            let (name, after) = match after.strip_prefix('(') {
+                Some(inner) => {
+                    let end = inner.find(')').ok_or(PreprocessErrorKind::Expression)?;
+                    (inner[..end].trim(), &inner[end + 1..])
+                }
+                None => split_ident(after),
+            };
+            if !is_name(name) {
+                return Err(PreprocessErrorKind::Expression);
+            }
+            out.push_str(if self.macros.contains_key(name) {
+                "1"
+            } else {
+                "0"
+            });
+            rest = after;
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// Expands the macros of `text`. Macros in `expanding` are not expanded again.
+    fn expand(
+        &self,
+        text: &str,
+        expanding: &mut Vec<String>,
+        depth: usize,
+    ) -> std::result::Result<String, PreprocessErrorKind> {
+        if depth >= MAX_DEPTH {
+            return Err(PreprocessErrorKind::Recursion);
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '"' | '\'' => string_len(rest),
+                _ if c.is_ascii_digit() => {
+                    rest.find(|c: char| !is_ident_char(c)).unwrap_or(rest.len())
+                }
+                _ if is_ident_start(c) => {
+                    let (ident, after) = split_ident(rest);
+                    let Some(m) = self.macros.get(ident) else {
+                        out.push_str(ident);
+                        rest = after;
+                        continue;
+                    };
+                    if expanding.iter().any(|e| e == ident) {
+                        out.push_str(ident);
+                        rest = after;
+                        continue;
+                    }
+                    let body = match &m.params {
+                        None => {
+                            rest = after;
+                            m.body.clone()
+                        }
+                        Some(params) => {
+                            // Parameterized macros are only expanded when called.
+                            let Some((args, after)) = call_args(after) else {
+                                out.push_str(ident);
+                                rest = after;
+                                continue;
+                            };
+                            let args = match (args.len(), params.len()) {
+                                (1, 0) if args[0].trim().is_empty() => vec![],
+                                (n, p) if n == p => args,
+                                _ => {
+                                    return Err(PreprocessErrorKind::MacroArguments(
+                                        ident.to_string(),
+                                    ))
+                                }
+                            };
+                            let args = args
+                                .iter()
+                                .map(|a| self.expand(a.trim(), expanding, depth + 1))
+                                .collect::<std::result::Result<Vec<_>, _>>()?;
+                            rest = after;
+                            substitute(&m.body, params, &args)
+                        }
+                    };
+                    expanding.push(ident.to_string());
+                    let expanded = self.expand(&body, expanding, depth + 1);
+                    expanding.pop();
+                    out.push_str(&expanded?);
+                    continue;
+                }
+                _ => c.len_utf8(),
+            };
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+        }
+        Ok(out)
+    }
+}
+
+/// Finds the file `name` in `dirs`.
+///
+/// The file must resolve inside of the directory it is found in once symbolic links and `..` are
+/// resolved, otherwise [`PreprocessErrorKind::Sandbox`] is returned, as for absolute paths.
+fn find_file<'p>(
+    name: &str,
+    dirs: impl IntoIterator<Item = &'p Path>,
+) -> std::result::Result<PathBuf, PreprocessErrorKind> {
+    if Path::new(name).is_absolute() {
+        return Err(PreprocessErrorKind::Sandbox(name.to_string()));
+    }
+    let mut escaped = false;
+    for dir in dirs {
+        let path = dir.join(name);
+        let (Ok(root), Ok(candidate)) = (dir.canonicalize(), path.canonicalize()) else {
+            continue;
+        };
+        if !candidate.starts_with(&root) {
+            escaped = true;
+        } else if candidate.is_file() {
+            return Ok(path);
+        }
+    }
+    Err(match escaped {
+        true => PreprocessErrorKind::Sandbox(name.to_string()),
+        false => PreprocessErrorKind::IncludeNotFound(name.to_string()),
+    })
+}
+
+/// Splits the label at the start of `line` if it is followed by a directive.
+fn split_label(line: &str) -> (Option<&str>, &str) {
+    if let Some((label, rest)) = line.split_once(':') {
+        let label = label.trim();
+        if layout::is_ident(label) && !rest.starts_with(':') && directive(rest).is_some() {
+            return (Some(label), rest);
+        }
+    }
+    (None, line)
+}
+
+/// Returns the name and arguments of the preprocessor directive on `line`, if any.
+fn directive(line: &str) -> Option<(&'static str, &str)> {
+    const DIRECTIVES: &[&str] = &[
+        "define", "undef", "ifdef", "ifndef", "if", "elif", "else", "endif", "include", "macro",
+        "endmacro", "error",
+    ];
+    let line = line.trim_start();
+    for (prefix, name) in [(".include", "include"), (".incbin", "incbin")] {
+        match line.strip_prefix(prefix) {
+            Some(args) if args.is_empty() || args.starts_with(char::is_whitespace) => {
+                return Some((name, args.trim_start()));
+            }
+            _ => {}
+        }
+    }
+    let rest = line.strip_prefix('#').or_else(|| line.strip_prefix('%'))?;
+    let (word, args) = split_ident(rest);
+    let name = DIRECTIVES.iter().find(|&&d| d == word)?;
+    (args.is_empty() || args.starts_with(|c: char| c.is_whitespace() || c == '('))
+        .then_some((*name, args.trim_start()))
+}
+
+/// Returns `true` if `c` can start a macro name.
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+/// Returns `true` if `c` can be part of a macro name.
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Returns `true` if `s` is a valid macro name.
+fn is_name(s: &str) -> bool {
+    s.starts_with(is_ident_start) && s.chars().all(is_ident_char)
+}
+
+/// Splits the identifier at the start of `s` from the rest.
+fn split_ident(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !is_ident_char(c)).unwrap_or(s.len()))
+}
+
+/// Returns the position of the identifier `ident` in `s`, if any.
+fn find_ident(s: &str, ident: &str) -> Option<usize> {
+    s.match_indices(ident).map(|(pos, _)| pos).find(|&pos| {
+        !s[..pos].ends_with(is_ident_char) && !s[pos + ident.len()..].starts_with(is_ident_char)
+    })
+}
+
+/// Returns the length of the string or character literal at the start of `s`.
+fn string_len(s: &str) -> usize {
+    let quote = s.as_bytes()[0];
+    let mut escaped = false;
+    for (idx, &b) in s.as_bytes().iter().enumerate().skip(1) {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            _ if b == quote => return idx + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Parses the arguments of a macro call at the start of `s`, returning them along with the rest
+/// of `s`, or `None` if `s` doesn't start with a parenthesized list.
+fn call_args(s: &str) -> Option<(Vec<String>, &str)> {
+    let inner = s.trim_start().strip_prefix('(')?;
+    let mut args = vec![];
+    let mut level = 0;
+    let mut start = 0;
+    let mut idx = 0;
+    while idx < inner.len() {
+        match inner.as_bytes()[idx] {
+            b'"' | b'\'' => {
+                idx += string_len(&inner[idx..]);
+                continue;
+            }
+            b'(' | b'[' => level += 1,
+            b')' if level == 0 => {
+                args.push(inner[start..idx].to_string());
+                return Some((args, &inner[idx + 1..]));
+            }
+            b')' | b']' => level -= 1,
+            b',' if level == 0 => {
+                args.push(inner[start..idx].to_string());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// Replaces the parameters of a macro body with the arguments of a call.
+fn substitute(body: &str, params: &[String], args: &[String]) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '"' || c == '\'' {
+            string_len(rest)
+        } else if is_ident_char(c) {
+            let (ident, _) = split_ident(rest);
+            match params.iter().position(|p| p == ident) {
+                Some(idx) => {
+                    out.push_str(&args[idx]);
+                    rest = &rest[ident.len()..];
+                    continue;
+                }
+                None => ident.len(),
+            }
+        } else {
+            c.len_utf8()
+        };
+        out.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    out
+}
+
+// -----------------------------------------------------------------------------------------------
+// Expressions
+// -----------------------------------------------------------------------------------------------
+
+/// Binary operators of `#if` expressions, by increasing precedence.
+const BINARY_OPS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Integer expression of an `#if` directive, in which undefined identifiers evaluate to 0.
+struct Expression<'a> {
+    /// Remaining input.
+    rest: &'a str,
+}
+
+impl<'a> Expression<'a> {
+    /// Creates a parser for `text`.
+    fn new(text: &'a str) -> Self {
+        Expression { rest: text }
+    }
+
+    /// Evaluates the whole expression.
+    fn evaluate(mut self) -> std::result::Result<i128, PreprocessErrorKind> {
+        let value = self.binary(0)?;
+        match self.rest.trim().is_empty() {
+            true => Ok(value),
+            false => Err(PreprocessErrorKind::Expression),
+        }
+    }
+
+    /// Consumes `token` if the input starts with it.
+    fn eat(&mut self, token: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        // `|` and `&` must not match the start of `||` and `&&`, nor `<` that of `<<` or `<=`.
+        let next = self.rest.get(1..).unwrap_or("");
+        let doubled = matches!(token, "|" | "&" | "<" | ">") && next.starts_with(token);
+        let longer = matches!(token, "<" | ">") && next.starts_with('=');
+        if self.rest.starts_with(token) && !doubled && !longer {
+            self.rest = &self.rest[token.len()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parses operators of precedence `level` and above.
+    fn binary(&mut self, level: usize) -> std::result::Result<i128, PreprocessErrorKind> {
+        let Some(ops) = BINARY_OPS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            for op in ops.iter() {
+                if self.eat(op) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = match *op {
+                        "||" => ((lhs != 0) || (rhs != 0)) as i128,
+                        "&&" => ((lhs != 0) && (rhs != 0)) as i128,
+                        "|" => lhs | rhs,
+                        "^" => lhs ^ rhs,
+                        "&" => lhs & rhs,
+                        "==" => (lhs == rhs) as i128,
+                        "!=" => (lhs != rhs) as i128,
+                        "<=" => (lhs <= rhs) as i128,
+                        ">=" => (lhs >= rhs) as i128,
+                        "<" => (lhs < rhs) as i128,
+                        ">" => (lhs > rhs) as i128,
+                        "<<" => lhs.checked_shl(rhs as u32).unwrap_or(0),
+                        ">>" => lhs.checked_shr(rhs as u32).unwrap_or(0),
+                        "+" => lhs.wrapping_add(rhs),
+                        "-" => lhs.wrapping_sub(rhs),
+                        "*" => lhs.wrapping_mul(rhs),
+                        "/" => lhs
+                            .checked_div(rhs)
+                            .ok_or(PreprocessErrorKind::Expression)?,
+                        _ => lhs
+                            .checked_rem(rhs)
+                            .ok_or(PreprocessErrorKind::Expression)?,
+                    };
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    /// Parses unary operators, parentheses and operands.
+    fn unary(&mut self) -> std::result::Result<i128, PreprocessErrorKind> {
+        if self.eat("!") {
+            return Ok((self.unary()? == 0) as i128);
+        }
+        if self.eat("~") {
+            return Ok(!self.unary()?);
+        }
+        if self.eat("-") {
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        if self.eat("(") {
+            let value = self.binary(0)?;
+            return match self.eat(")") {
+                true => Ok(value),
+                false => Err(PreprocessErrorKind::Expression),
+            };
+        }
+        let (token, rest) = split_ident(self.rest);
+        self.rest = rest;
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            layout::parse_int(token).ok_or(PreprocessErrorKind::Expression)
+        } else if is_name(token) {
+            Ok(0)
+        } else {
+            Err(PreprocessErrorKind::Expression)
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi;
+
+    fn lines(pp: &Preprocessed) -> Vec<usize> {
+        pp.lines.iter().map(|l| l.line).collect()
+    }
+
+    #[test]
+    fn test_defines() {
+        let pp = Preprocessor::new()
+            .define("SIZE", "0x10")
+            .process(
+                "#define ADD(a, b) add a, b\n\
+                 #define REG eax\n\
+                 ADD(REG, SIZE)\n\
+                 mov ebx, \"SIZE\"\n\
+                 #undef REG\n\
+                 ADD(REG, (1 + 2))\n\
+                 .L1: ADD",
+            )
+            .unwrap();
+        assert_eq!(
+            pp.source,
+            "add eax, 0x10\nmov ebx, \"SIZE\"\nadd REG, (1 + 2)\n.L1: ADD\n"
+        );
+        assert_eq!(lines(&pp), vec![3, 4, 6, 7]);
+
+        let err = Preprocessor::new()
+            .process("#define F(a) a\nnop\nF(1, 2)")
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            PreprocessErrorKind::MacroArguments("F".to_string())
+        );
+        assert_eq!(
+            err.to_string(),
+            "<input>:3: wrong number of arguments for macro F"
+        );
+
+        let pp = Preprocessor::new()
+            .process("#define A B\n#define B A\nA")
+            .unwrap();
+        assert_eq!(pp.source, "A\n");
+    }
+
+    #[test]
+    fn test_macros() {
+        let pp = Preprocessor::new()
+            .process(
+                "%macro PUSH2(a, b)\n\
+                 push a\n\
+                 push b\n\
+                 %endmacro\n\
+                 PUSH2(eax, ebx)",
+            )
+            .unwrap();
+        assert_eq!(pp.source, "push eax\npush ebx\n");
+        assert_eq!(lines(&pp), vec![5, 5]);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = "#ifdef X64\n\
+                      push rax\n\
+                      #elif defined(X86) && VERSION >= 2\n\
+                      push eax\n\
+                      #else\n\
+                      push ax\n\
+                      #endif";
+        let pp = Preprocessor::new().define("X64", "");
+        assert_eq!(pp.process(source).unwrap().source, "push rax\n");
+        let pp = Preprocessor::new().define("X86", "").define("VERSION", "2");
+        assert_eq!(pp.process(source).unwrap().source, "push eax\n");
+        let pp = Preprocessor::new().define("X86", "").define("VERSION", "1");
+        let pp = pp.process(source).unwrap();
+        assert_eq!(pp.source, "push ax\n");
+        assert_eq!(lines(&pp), vec![6]);
+
+        let pp = Preprocessor::new();
+        assert_eq!(
+            pp.process("#if (1 << 4) == 0x10 && !0\nnop\n#endif\n# comment")
+                .unwrap()
+                .source,
+            "nop\n# comment\n"
+        );
+        let err = pp.process("#if 1\nnop").unwrap_err();
+        assert_eq!(err.kind, PreprocessErrorKind::Conditional);
+        let err = pp.process("#endif").unwrap_err();
+        assert_eq!(err.kind, PreprocessErrorKind::Conditional);
+        let err = pp.process("#if 1 +\n#endif").unwrap_err();
+        assert_eq!(err.kind, PreprocessErrorKind::Expression);
+        let err = pp
+            .process("#if 0\n#error no\n#else\n#error yes\n#endif")
+            .unwrap_err();
+        assert_eq!(err.kind, PreprocessErrorKind::User("yes".to_string()));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("keystone-pp-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(
+            dir.join("defs.inc"),
+            "#define ONE 1\n.include \"sub/code.inc\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("sub/code.inc"), "mov eax, ONE\n#error oops\n").unwrap();
+
+        let pp = Preprocessor::new().include_dir(&dir);
+        let err = pp.process("nop\n%include \"defs.inc\"").unwrap_err();
+        assert_eq!(err.kind, PreprocessErrorKind::User("oops".to_string()));
+        assert_eq!(err.location.file, Some(dir.join("sub/code.inc")));
+        assert_eq!(err.location.line, 2);
+
+        std::fs::write(dir.join("sub/code.inc"), "mov eax, ONE\n").unwrap();
+        let out = pp.process("nop\n#include <defs.inc>").unwrap();
+        assert_eq!(out.source, "nop\nmov eax, 1\n");
+        assert_eq!(out.lines[1].line, 1);
+        assert_eq!(out.lines[1].file, Some(dir.join("sub/code.inc")));
+        let out = pp.process("start: .include \"defs.inc\"").unwrap();
+        assert_eq!(out.source, "start:\nmov eax, 1\n");
+
+        let absolute = dir.join("defs.inc");
+        let err = pp
+            .process(&format!("%include \"{}\"", absolute.display()))
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            PreprocessErrorKind::Sandbox(absolute.display().to_string())
+        );
+        let outside = format!("keystone-pp-{}.inc", std::process::id());
+        std::fs::write(std::env::temp_dir().join(&outside), "nop\n").unwrap();
+        let err = pp.process(&format!("%include \"sub/../../{}\"", outside));
+        std::fs::remove_file(std::env::temp_dir().join(&outside)).unwrap();
+        assert_eq!(
+            err.unwrap_err().kind,
+            PreprocessErrorKind::Sandbox(format!("sub/../../{}", outside))
+        );
+
+        let err = Preprocessor::new()
+            .process("%include \"defs.inc\"")
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            PreprocessErrorKind::IncludeNotFound("defs.inc".to_string())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incbin() {
+        let root = std::env::temp_dir().join(format!("keystone-incbin-{}", std::process::id()));
+        let dir = root.join("blobs");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("blob.bin"), (0..20).collect::<Vec<u8>>()).unwrap();
+        std::fs::write(root.join("secret.bin"), [0xff]).unwrap();
+
+        let pp = Preprocessor::new().include_dir(&dir).define("SKIP", "2");
+        let out = pp
+            .process("nop\n.incbin \"blob.bin\", SKIP, 3\nend:")
+            .unwrap();
+        assert_eq!(out.source, "nop\n.byte 0x02, 0x03, 0x04\nend:\n");
+        let out = pp.process(".incbin \"blob.bin\"").unwrap();
+        assert_eq!(out.source.lines().count(), 2);
+        assert_eq!(lines(&out), vec![1, 1]);
+        let out = pp.process("data: .incbin \"blob.bin\", 0, 1").unwrap();
+        assert_eq!(out.source, "data:\n.byte 0x00\n");
+        let out = pp
+            .process("%macro BLOB(n)\n.incbin \"blob.bin\", n, 1\n%endmacro\nnop\nBLOB(5)")
+            .unwrap();
+        assert_eq!(out.source, "nop\n.byte 0x05\n");
+        assert_eq!(lines(&out), vec![4, 5]);
+
+        let err = pp.process(".incbin \"../secret.bin\"").unwrap_err();
+        assert_eq!(
+            err.kind,
+            PreprocessErrorKind::Sandbox("../secret.bin".to_string())
+        );
+        let err = pp.process(".incbin \"blob.bin\", 10, 11").unwrap_err();
+        assert_eq!(err.kind, PreprocessErrorKind::Directive);
+        let err = Preprocessor::new()
+            .process(".incbin \"blob.bin\"")
+            .unwrap_err();
+        assert_eq!(
+            err.kind,
+            PreprocessErrorKind::IncludeNotFound("blob.bin".to_string())
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_assemble() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let pp = Preprocessor::new()
+            .process("#define BAD invalid\nnop\n\nBAD")
+            .unwrap();
+        let err = pp.assemble(&engine, 0).unwrap_err();
+        assert_eq!(err.location.map(|l| l.line), Some(4));
+    }
+}
@@ -0,0 +1,368 @@
+//! Multi-section assembly.
+//!
+//! Keystone assembles `.section`, `.text` and `.data` directives but returns the content of all
+//! sections as a single buffer. [`SectionAssembler`] splits the program on these directives
+//! instead, and lays out every section at its own base address, so that code and data assembled
+//! together can be placed separately, e.g. by a loader or an ELF writer. Labels can be referenced
+//! across sections.
+//!
+//! Sections are placed one after the other, in the order they first appear and aligned on their
+//! largest alignment directive, unless their address is given with [`SectionAssembler::base`].
+//! Sections may not overlap.
+//! Statements before the first section directive belong to `.text`. Equates and other
+//! definitions apply to the statements that follow them in every section.
+//!
+//! ```no_run
+//! use keystone_engine::sections::SectionAssembler;
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let output = SectionAssembler::new(&engine, 0x1000)
+//!     .base(".data", 0x8000)
+//!     .assemble(".text\nmov eax, [value]\nret\n.data\nvalue: .long 42")
+//!     .expect("Could not assemble");
+//! assert_eq!(output.section(".data").unwrap().address, 0x8000);
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use crate::layout::{self, Statement, StatementKind, MAX_PASSES};
+use crate::{ffi, Keystone, MiscError, Result};
+
+/// Section containing the statements that precede any section directive.
+const DEFAULT_SECTION: &str = ".text";
+
+/// Section of an assembled program.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Section {
+    /// Name of the section, e.g. `.text`.
+    pub name: String,
+    /// Base address of the section.
+    pub address: u64,
+    /// Alignment of the section in bytes, which is at least 1.
+    pub alignment: u64,
+    /// Content of the section.
+    pub bytes: Vec<u8>,
+}
+
+impl Section {
+    /// Returns the address following the end of the section.
+    pub fn end(&self) -> u64 {
+        self.address + self.bytes.len() as u64
+    }
+}
+
+/// Output of a multi-section assembly.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SectionedOutput {
+    /// Sections of the program, in the order they first appear in the source.
+    pub sections: Vec<Section>,
+    /// Addresses of the labels defined by the program.
+    pub labels: HashMap<String, u64>,
+}
+
+impl SectionedOutput {
+    /// Returns the section named `name`, if any.
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+}
+
+/// Statements of a section, before layout.
+#[derive(Debug)]
+struct Pending {
+    /// Name of the section.
+    name: String,
+    /// Statements of the section, including the definitions that precede them.
+    statements: Vec<Statement>,
+}
+
+/// Returns the name of the section selected by a directive, or `None` if `code` isn't a section
+/// directive.
+fn section_directive(code: &str) -> Option<String> {
+    let code = code.trim();
+    let word = code
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match word.as_str() {
+        ".text" | ".data" | ".bss" | ".rodata" => Some(word),
+        ".section" => {
+            let name = code[word.len()..]
+                .split(',')
+                .next()?
+                .trim()
+                .trim_matches('"');
+            (!name.is_empty()).then(|| name.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the alignment in bytes requested by an alignment directive.
+fn alignment(arch: ffi::Arch, code: &str) -> Option<u64> {
+    let code = code.trim();
+    let (word, args) = code.split_at(code.find(char::is_whitespace)?);
+    let value = u32::try_from(layout::parse_int(args.split(',').next()?)?).ok()?;
+    // `.align` takes a power of two on these architectures, and a number of bytes elsewhere.
+    let pow2 = matches!(
+        arch,
+        ffi::Arch::ARM | ffi::Arch::ARM64 | ffi::Arch::MIPS | ffi::Arch::PPC
+    );
+    match word.to_ascii_lowercase().as_str() {
+        ".p2align" => 1u64.checked_shl(value),
+        ".align" if pow2 => 1u64.checked_shl(value),
+        _ => Some(u64::from(value)),
+    }
+}
+
+/// Assembles programs made of several sections.
+#[derive(Debug)]
+pub struct SectionAssembler<'a> {
+    /// Keystone instance used to assemble the sections.
+    engine: &'a Keystone,
+    /// Address of the first section without an explicit base address.
+    address: u64,
+    /// Explicit base addresses of the sections.
+    bases: HashMap<String, u64>,
+}
+
+impl<'a> SectionAssembler<'a> {
+    /// Creates a new assembler placing the sections from `address`.
+    pub fn new(engine: &'a Keystone, address: u64) -> Self {
+        SectionAssembler {
+            engine,
+            address,
+            bases: HashMap::new(),
+        }
+    }
+
+    /// Places the section `name` at `address`.
+    ///
+    /// The other sections are still placed from the address given to [`SectionAssembler::new`],
+    /// and [`SectionAssembler::assemble`] fails if they overlap this one.
+    pub fn base(mut self, name: &str, address: u64) -> Self {
+        self.bases.insert(name.to_string(), address);
+        self
+    }
+
+    /// Splits the statements of `source` into sections.
+    fn split(&self, source: &str) -> Vec<Pending> {
+        let mut sections: Vec<Pending> = vec![];
+        let mut definitions: Vec<Statement> = vec![];
+        let mut current = 0;
+        for stmt in layout::parse(self.engine.arch(), source) {
+            let name = section_directive(&stmt.code);
+            if stmt.kind == StatementKind::Definition {
+                for section in sections.iter_mut() {
+                    section.statements.push(stmt.clone());
+                }
+                definitions.push(stmt);
+                continue;
+            }
+            if sections.is_empty()
+                && name.is_none()
+                && stmt.kind == StatementKind::Empty
+                && stmt.label.is_none()
+            {
+                continue;
+            }
+            let name = name.unwrap_or_else(|| {
+                sections
+                    .get(current)
+                    .map_or(DEFAULT_SECTION.to_string(), |s| s.name.clone())
+            });
+            current = match sections.iter().position(|s| s.name == name) {
+                Some(idx) => idx,
+                None => {
+                    sections.push(Pending {
+                        name,
+                        statements: definitions.clone(),
+                    });
+                    sections.len() - 1
+                }
+            };
+            // The directive itself is dropped, but not the label it may define.
+            let stmt = match section_directive(&stmt.code) {
+                Some(_) => Statement::new(stmt.line, stmt.column, stmt.label, String::new()),
+                None => stmt,
+            };
+            sections[current].statements.push(stmt);
+        }
+        sections
+    }
+
+    /// Assembles `source` and returns its sections.
+    ///
+    /// Returns the error of the first statement that could not be assembled, if any, and
+    /// [`MiscError::AddressRange`] if two sections overlap or a section extends beyond the end
+    /// of the address space.
+    pub fn assemble(&self, source: &str) -> Result<SectionedOutput> {
+        let pending = self.split(source);
+        let arch = self.engine.arch();
+        let alignments: Vec<u64> = pending
+            .iter()
+            .map(|p| {
+                p.statements
+                    .iter()
+                    .filter(|s| s.kind == StatementKind::Align)
+                    .filter_map(|s| alignment(arch, &s.code))
+                    .fold(1, u64::max)
+            })
+            .collect();
+        let defined: HashSet<&str> = pending
+            .iter()
+            .flat_map(|p| p.statements.iter().filter_map(|s| s.label.as_deref()))
+            .collect();
+        let mut labels: HashMap<String, u64> = HashMap::new();
+        let mut layouts = vec![];
+        for _ in 0..MAX_PASSES {
+            let mut next_labels = HashMap::new();
+            let mut next = self.address;
+            layouts.clear();
+            for (section, &align) in pending.iter().zip(&alignments) {
+                let base = match self.bases.get(&section.name) {
+                    Some(&base) => base,
+                    None => next
+                        .div_ceil(align)
+                        .checked_mul(align)
+                        .ok_or(MiscError::AddressRange)?,
+                };
+                // Labels of the other sections come from the previous pass. Those that haven't
+                // been placed yet resolve to the base of the section for now.
+                let lay =
+                    layout::layout(self.engine, &section.statements, base, |sym| {
+                        match labels.get(sym) {
+                            Some(&value) => Some(value),
+                            None => defined.contains(sym).then_some(base),
+                        }
+                    });
+                let size: u64 = lay
+                    .placed
+                    .iter()
+                    .filter_map(|p| p.bytes.as_ref().ok().map(|b| b.len() as u64 + p.gap))
+                    .sum();
+                if !self.bases.contains_key(&section.name) {
+                    next = base.checked_add(size).ok_or(MiscError::AddressRange)?;
+                }
+                next_labels.extend(lay.labels.clone());
+                layouts.push((base, lay));
+            }
+            let stable = next_labels == labels;
+            labels = next_labels;
+            if stable {
+                break;
+            }
+        }
+        // Errors are reported in source order.
+        let mut first_err: Option<(usize, usize, crate::KeystoneError)> = None;
+        let mut sections = vec![];
+        for ((section, &alignment), (address, lay)) in pending.iter().zip(&alignments).zip(layouts)
+        {
+            let mut bytes = vec![];
+            for (stmt, placed) in section.statements.iter().zip(lay.placed) {
+                match placed.into_bytes() {
+                    Ok(b) => bytes.extend(b),
+                    Err(e) => {
+                        if first_err.is_none_or(|(l, c, _)| (stmt.line, stmt.column) < (l, c)) {
+                            first_err = Some((stmt.line, stmt.column, e));
+                        }
+                    }
+                }
+            }
+            sections.push(Section {
+                name: section.name.clone(),
+                address,
+                alignment,
+                bytes,
+            });
+        }
+        if let Some((_, _, e)) = first_err {
+            return Err(e);
+        }
+        // Sections with an explicit base may land on the others.
+        let mut ranges = sections
+            .iter()
+            .filter(|s| !s.bytes.is_empty())
+            .map(|s| {
+                let end = s.address.checked_add(s.bytes.len() as u64)?;
+                Some((s.address, end))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(MiscError::AddressRange)?;
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|w| w[1].0 < w[0].1) {
+            return Err(MiscError::AddressRange)?;
+        }
+        Ok(SectionedOutput { sections, labels })
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_directive() {
+        assert_eq!(section_directive(".data").as_deref(), Some(".data"));
+        assert_eq!(
+            section_directive(".section .rodata.str, \"a\"").as_deref(),
+            Some(".rodata.str")
+        );
+        assert_eq!(section_directive("mov eax, 1"), None);
+        assert_eq!(alignment(ffi::Arch::X86, ".align 16"), Some(16));
+        assert_eq!(alignment(ffi::Arch::ARM, ".align 4"), Some(16));
+        assert_eq!(alignment(ffi::Arch::ARM, ".p2align 3"), Some(8));
+    }
+
+    #[test]
+    fn test_sections() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let output = SectionAssembler::new(&engine, 0x1000)
+            .assemble(
+                "mov eax, [value]\n\
+                 .data\n\
+                 .align 16\n\
+                 value: .long 0x2a\n\
+                 .text\n\
+                 ret",
+            )
+            .unwrap();
+        assert_eq!(output.sections.len(), 2);
+        let text = output.section(".text").unwrap();
+        assert_eq!(text.address, 0x1000);
+        assert_eq!(text.bytes, vec![0xa1, 0x10, 0x10, 0x00, 0x00, 0xc3]);
+        let data = output.section(".data").unwrap();
+        assert_eq!(data.address, 0x1010);
+        assert_eq!(data.alignment, 16);
+        assert_eq!(data.bytes, vec![0x2a, 0x00, 0x00, 0x00]);
+        assert_eq!(output.labels.get("value"), Some(&0x1010));
+
+        let output = SectionAssembler::new(&engine, 0x1000)
+            .base(".data", 0x8000)
+            .assemble(".data\nvalue: .long 1\n.text\nmov eax, [value]")
+            .unwrap();
+        assert_eq!(output.section(".text").unwrap().address, 0x1000);
+        assert_eq!(
+            output.section(".text").unwrap().bytes,
+            vec![0xa1, 0x00, 0x80, 0x00, 0x00]
+        );
+
+        let output = SectionAssembler::new(&engine, 0x1000)
+            .base(".data", 0x1002)
+            .assemble(".data\nvalue: .long 1\n.text\nmov eax, [value]");
+        assert_eq!(output, Err(MiscError::AddressRange.into()));
+        let output =
+            SectionAssembler::new(&engine, u64::MAX - 1).assemble("nop\n.data\n.align 16\n.long 1");
+        assert_eq!(output, Err(MiscError::AddressRange.into()));
+        let output = SectionAssembler::new(&engine, 0x1000)
+            .base(".data", u64::MAX - 1)
+            .assemble(".data\n.long 1");
+        assert_eq!(output, Err(MiscError::AddressRange.into()));
+    }
+}
@@ -0,0 +1,337 @@
+//! Stack string construction.
+//!
+//! Position-independent code can't easily reference data, so strings are often built on the
+//! stack instead. [`build`] generates the sequence of pushes or stores writing a byte string to
+//! the stack for the architecture and word size of a Keystone instance, and assembles it.
+//!
+//! The string is padded with NUL bytes up to the stack alignment of the architecture (the word
+//! size, or 16 bytes on ARM64), and the stack pointer points to its first byte once the sequence
+//! has run. A NUL terminator is only present if it's part of the input or of the padding.
+//!
+//! Besides the stack pointer, the sequences clobber `rax` and `rdx` on x86-64, `r1` and `r2` on
+//! ARM, `x1` to `x4` on ARM64 and `$t0` and `$t1` on MIPS, while x86 sequences don't use any
+//! register. On x86 and x86-64, the flags are clobbered as well when forbidden bytes are avoided.
+//!
+//! When forbidden bytes are given, words whose encoding contains them are loaded as two values
+//! xored together, and MIPS stack offsets whose encoding contains them are split in two
+//! additions. x86 engines are expected to use the Intel syntax.
+//!
+//! ```no_run
+//! use keystone_engine::badbytes::BadBytes;
+//! use keystone_engine::stackstr;
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_32).expect("Could not initialize Keystone");
+//! let res = stackstr::build(&engine, b"/bin/sh\0", &BadBytes::from(&b"\x00"[..]))
+//!     .expect("Could not build stack string");
+//! println!("{} bytes of code: {}", res.output.size, res.source);
+//! ```
+
+use crate::badbytes::{xor_key, BadBytes};
+use crate::{ffi, Keystone, KeystoneOutput, MiscError, Result};
+
+/// Sequence writing a string to the stack.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct StackString {
+    /// Assembly source of the sequence.
+    pub source: String,
+    /// Encoding of the sequence, whose length is given by [`KeystoneOutput::size`].
+    pub output: KeystoneOutput,
+    /// Number of bytes written to the stack, padding included.
+    pub stack_size: usize,
+}
+
+/// Targets supported by the stack string builder.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Target {
+    /// x86, using `push imm32`.
+    X86,
+    /// x86-64, using `push imm32` or `mov rax, imm64` followed by `push rax`.
+    X64,
+    /// ARM, using `movw`/`movt` and `push`.
+    Arm,
+    /// ARM64, using `movz`/`movk` and `stp`.
+    Arm64,
+    /// MIPS32, using `li` and `sw`.
+    Mips,
+}
+
+impl Target {
+    /// Returns the target matching the configuration of `engine`.
+    fn from_engine(engine: &Keystone) -> Result<Self> {
+        let mode = engine.mode();
+        match engine.arch() {
+            ffi::Arch::X86 if mode.contains(ffi::Mode::MODE_64) => Ok(Target::X64),
+            ffi::Arch::X86 if mode.contains(ffi::Mode::MODE_32) => Ok(Target::X86),
+            ffi::Arch::ARM => Ok(Target::Arm),
+            ffi::Arch::ARM64 => Ok(Target::Arm64),
+            ffi::Arch::MIPS if !mode.contains(ffi::Mode::MIPS64) => Ok(Target::Mips),
+            ffi::Arch::X86 | ffi::Arch::MIPS => Err(ffi::Error::MODE)?,
+            _ => Err(ffi::Error::ARCH)?,
+        }
+    }
+
+    /// Returns the size of a word in bytes.
+    fn word_size(self) -> usize {
+        match self {
+            Target::X64 | Target::Arm64 => 8,
+            _ => 4,
+        }
+    }
+
+    /// Returns the number of bytes written at once, which is also the stack alignment.
+    fn unit_size(self) -> usize {
+        match self {
+            Target::Arm64 => 16,
+            _ => self.word_size(),
+        }
+    }
+
+    /// Returns the instructions loading `value` into `reg`.
+    fn load(self, reg: &str, value: u64) -> Vec<String> {
+        match self {
+            Target::X86 | Target::X64 => vec![format!("mov {}, {:#x}", reg, value)],
+            Target::Arm => {
+                let mut insns = vec![format!("movw {}, #{:#x}", reg, value & 0xffff)];
+                if value >> 16 != 0 {
+                    insns.push(format!("movt {}, #{:#x}", reg, value >> 16));
+                }
+                insns
+            }
+            Target::Arm64 => {
+                let mut insns = vec![format!("movz {}, #{:#x}", reg, value & 0xffff)];
+                for shift in [16, 32, 48] {
+                    let chunk = (value >> shift) & 0xffff;
+                    if chunk != 0 {
+                        insns.push(format!("movk {}, #{:#x}, lsl #{}", reg, chunk, shift));
+                    }
+                }
+                insns
+            }
+            Target::Mips => vec![format!("li {}, {:#x}", reg, value)],
+        }
+    }
+
+    /// Returns the instructions loading `value` into `reg` as `a ^ k`, using `tmp` for `k`.
+    fn load_split(self, reg: &str, tmp: &str, a: u64, k: u64) -> Vec<String> {
+        let mut insns = self.load(reg, a);
+        insns.extend(self.load(tmp, k));
+        insns.push(match self {
+            Target::X86 | Target::X64 => format!("xor {}, {}", reg, tmp),
+            Target::Arm | Target::Arm64 => format!("eor {}, {}, {}", reg, reg, tmp),
+            Target::Mips => format!("xor {}, {}, {}", reg, reg, tmp),
+        });
+        insns
+    }
+
+    /// Returns the candidate sequences writing the words of a unit, from the most to the least
+    /// preferred.
+    ///
+    /// `offset` is the offset of the unit from the final stack pointer, only used by targets
+    /// that don't push.
+    fn candidates(self, bad: &BadBytes, words: &[u64], offset: usize) -> Vec<Vec<String>> {
+        let bits = 8 * self.word_size() as u32;
+        let key = |v: u64| xor_key(bad, v, bits).map(|k| (v ^ k, k));
+        let mut cands = vec![];
+        match self {
+            Target::X86 => {
+                let v = words[0];
+                cands.push(vec![format!("push {:#x}", v)]);
+                if let Some((a, k)) = key(v) {
+                    cands.push(vec![
+                        format!("push {:#x}", a),
+                        format!("xor dword ptr [esp], {:#x}", k),
+                    ]);
+                }
+            }
+            Target::X64 => {
+                let v = words[0];
+                // `push imm32` sign-extends its operand to 64 bits.
+                if let Ok(imm) = i32::try_from(v as i64) {
+                    cands.push(vec![format!("push {}", imm)]);
+                }
+                let mut insns = self.load("rax", v);
+                insns.push("push rax".to_string());
+                cands.push(insns);
+                if let Some((a, k)) = key(v) {
+                    let mut insns = self.load_split("rax", "rdx", a, k);
+                    insns.push("push rax".to_string());
+                    cands.push(insns);
+                }
+            }
+            Target::Arm => {
+                let v = words[0];
+                let mut insns = self.load("r1", v);
+                insns.push("push {r1}".to_string());
+                cands.push(insns);
+                if let Some((a, k)) = key(v) {
+                    let mut insns = self.load_split("r1", "r2", a, k);
+                    insns.push("push {r1}".to_string());
+                    cands.push(insns);
+                }
+            }
+            Target::Arm64 => {
+                let mut insns = self.load("x1", words[0]);
+                insns.extend(self.load("x2", words[1]));
+                insns.push("stp x1, x2, [sp, #-16]!".to_string());
+                cands.push(insns);
+                if let (Some((a1, k1)), Some((a2, k2))) = (key(words[0]), key(words[1])) {
+                    let mut insns = self.load_split("x1", "x3", a1, k1);
+                    insns.extend(self.load_split("x2", "x4", a2, k2));
+                    insns.push("stp x1, x2, [sp, #-16]!".to_string());
+                    cands.push(insns);
+                }
+            }
+            Target::Mips => {
+                let v = words[0];
+                let mut loads = vec![self.load("$t0", v)];
+                if let Some((a, k)) = key(v) {
+                    loads.push(self.load_split("$t0", "$t1", a, k));
+                }
+                let mut stores = vec![vec![format!("sw $t0, {}($sp)", offset)]];
+                if let Some((x, y)) = split_imm16(bad, offset as i64) {
+                    stores.push(vec![
+                        format!("addiu $t1, $sp, {}", x),
+                        format!("sw $t0, {}($t1)", y),
+                    ]);
+                }
+                for load in &loads {
+                    for store in &stores {
+                        cands.push([load.as_slice(), store.as_slice()].concat());
+                    }
+                }
+            }
+        }
+        cands
+    }
+
+    /// Returns the candidate sequences reserving `size` bytes of stack on MIPS, from the most to
+    /// the least preferred.
+    fn reserve(self, bad: &BadBytes, size: usize) -> Vec<Vec<String>> {
+        let size = -(size as i64);
+        let mut cands = vec![vec![format!("addiu $sp, $sp, {}", size)]];
+        if let Some((x, y)) = split_imm16(bad, size) {
+            cands.push(vec![
+                format!("addiu $sp, $sp, {}", x),
+                format!("addiu $sp, $sp, {}", y),
+            ]);
+        }
+        cands
+    }
+}
+
+/// Splits `value` into two signed 16-bit immediates adding up to it, whose encodings are free
+/// of bad bytes.
+fn split_imm16(bad: &BadBytes, value: i64) -> Option<(i64, i64)> {
+    let clean = |v: i64| bad.is_clean(&(v as u16).to_le_bytes());
+    (1..0x8000)
+        .flat_map(|k| [k, -k])
+        .map(|k| (value - k, k))
+        .find(|&(x, k)| i16::try_from(x).is_ok() && clean(x) && clean(k))
+}
+
+/// Returns the first candidate sequence whose encoding is free of bad bytes.
+fn choose(engine: &Keystone, bad: &BadBytes, cands: Vec<Vec<String>>) -> Result<Vec<String>> {
+    for cand in cands {
+        if bad.is_empty() || bad.is_clean(&engine.asm(cand.join("\n"), 0)?.bytes) {
+            return Ok(cand);
+        }
+    }
+    Err(MiscError::BadBytes)?
+}
+
+/// Generates and assembles the sequence writing `data` to the stack.
+///
+/// Pass an empty [`BadBytes`] set if the encoding isn't constrained. Returns
+/// [`MiscError::BadBytes`] if the forbidden bytes couldn't be avoided.
+pub fn build(engine: &Keystone, data: &[u8], bad: &BadBytes) -> Result<StackString> {
+    let target = Target::from_engine(engine)?;
+    let big_endian = engine.mode().contains(ffi::Mode::BIG_ENDIAN);
+    let unit = target.unit_size();
+    let word = target.word_size();
+    let stack_size = data.len().div_ceil(unit) * unit;
+    let mut padded = data.to_vec();
+    padded.resize(stack_size, 0);
+    let mut insns = vec![];
+    if target == Target::Mips {
+        insns.extend(choose(engine, bad, target.reserve(bad, stack_size))?);
+    }
+    // Units are pushed from the end of the string, so that the first one ends up at the top of
+    // the stack.
+    for (idx, chunk) in padded.chunks(unit).enumerate().rev() {
+        let words: Vec<u64> = chunk
+            .chunks(word)
+            .map(|w| {
+                let mut buf = [0u8; 8];
+                if big_endian {
+                    buf[8 - word..].copy_from_slice(w);
+                    u64::from_be_bytes(buf)
+                } else {
+                    buf[..word].copy_from_slice(w);
+                    u64::from_le_bytes(buf)
+                }
+            })
+            .collect();
+        insns.extend(choose(
+            engine,
+            bad,
+            target.candidates(bad, &words, idx * unit),
+        )?);
+    }
+    let source = insns.join("\n");
+    let output = engine.asm(source.clone(), 0)?;
+    if !bad.is_clean(&output.bytes) {
+        return Err(MiscError::BadBytes.into());
+    }
+    Ok(StackString {
+        source,
+        output,
+        stack_size,
+    })
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let bad = BadBytes::from(&b"\x00"[..]);
+        let cands = Target::X86.candidates(&bad, &[0x0068732f], 4);
+        assert_eq!(cands[0], vec!["push 0x68732f"]);
+        assert_eq!(
+            cands[1],
+            vec!["push 0x169722e", "xor dword ptr [esp], 0x1010101"]
+        );
+        let cands = Target::Mips.candidates(&bad, &[0x2f62696e], 4);
+        assert_eq!(cands[0], vec!["li $t0, 0x2f62696e", "sw $t0, 4($sp)"]);
+        // `sw $t0, 0($sp)` encodes a zero offset.
+        let cands = Target::Mips.candidates(&bad, &[0x2f62696e], 0);
+        assert_eq!(
+            cands[1],
+            vec![
+                "li $t0, 0x2f62696e",
+                "addiu $t1, $sp, -257",
+                "sw $t0, 257($t1)"
+            ]
+        );
+        assert_eq!(split_imm16(&bad, -8), Some((-7, -1)));
+        assert_eq!(split_imm16(&BadBytes::new(), -8), Some((-9, 1)));
+    }
+
+    #[test]
+    fn test_build() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let res = build(&engine, b"/bin/sh", &BadBytes::new()).unwrap();
+        assert_eq!(res.source, "push 0x68732f\npush 0x6e69622f");
+        assert_eq!(res.stack_size, 8);
+        assert_eq!(res.output.size, 10);
+        let bad = BadBytes::from(&b"\x00"[..]);
+        let res = build(&engine, b"/bin/sh", &bad).unwrap();
+        assert!(bad.is_clean(&res.output.bytes));
+    }
+}
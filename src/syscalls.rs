@@ -0,0 +1,489 @@
+//! Linux system call sequences.
+//!
+//! Every architecture has its own system call numbers, argument registers and trap instruction.
+//! This module knows them for x86, x86-64, ARM (EABI), ARM64 and MIPS (o32), generates the
+//! assembly of a system call with its arguments and assembles it with a matching Keystone
+//! instance.
+//!
+//! ```no_run
+//! use keystone_engine::syscalls::{self, Arg};
+//! use keystone_engine::{Arch, Keystone, Mode};
+//!
+//! let engine = Keystone::new(Arch::X86, Mode::MODE_64).expect("Could not initialize Keystone");
+//! let output = syscalls::assemble(&engine, "exit", &[Arg::Imm(0)])
+//!     .expect("Could not assemble system call");
+//! println!("exit(0): {}", output);
+//! ```
+//!
+//! Arguments are loaded in order, so a register argument must not be one of the argument
+//! registers written before it.
+
+use crate::{ffi, Keystone, KeystoneOutput, MiscError, Result};
+
+// -----------------------------------------------------------------------------------------------
+// Tables
+// -----------------------------------------------------------------------------------------------
+
+/// System call numbers for x86.
+const X86_TABLE: &[(&str, u32)] = &[
+    ("exit", 1),
+    ("fork", 2),
+    ("read", 3),
+    ("write", 4),
+    ("open", 5),
+    ("close", 6),
+    ("unlink", 10),
+    ("execve", 11),
+    ("chdir", 12),
+    ("chmod", 15),
+    ("lseek", 19),
+    ("getpid", 20),
+    ("setuid", 23),
+    ("getuid", 24),
+    ("kill", 37),
+    ("mkdir", 39),
+    ("dup", 41),
+    ("pipe", 42),
+    ("brk", 45),
+    ("setgid", 46),
+    ("geteuid", 49),
+    ("ioctl", 54),
+    ("dup2", 63),
+    ("getppid", 64),
+    ("setsid", 66),
+    ("setreuid", 70),
+    ("mmap", 90),
+    ("munmap", 91),
+    ("socketcall", 102),
+    ("wait4", 114),
+    ("clone", 120),
+    ("mprotect", 125),
+    ("nanosleep", 162),
+    ("setresuid", 164),
+    ("getcwd", 183),
+    ("mmap2", 192),
+    ("exit_group", 252),
+    ("openat", 295),
+    ("socket", 359),
+    ("bind", 361),
+    ("connect", 362),
+    ("listen", 363),
+    ("accept4", 364),
+];
+
+/// System call numbers for x86-64.
+const X64_TABLE: &[(&str, u32)] = &[
+    ("read", 0),
+    ("write", 1),
+    ("open", 2),
+    ("close", 3),
+    ("lseek", 8),
+    ("mmap", 9),
+    ("mprotect", 10),
+    ("munmap", 11),
+    ("brk", 12),
+    ("ioctl", 16),
+    ("pipe", 22),
+    ("dup", 32),
+    ("dup2", 33),
+    ("nanosleep", 35),
+    ("getpid", 39),
+    ("socket", 41),
+    ("connect", 42),
+    ("accept", 43),
+    ("sendto", 44),
+    ("recvfrom", 45),
+    ("bind", 49),
+    ("listen", 50),
+    ("clone", 56),
+    ("fork", 57),
+    ("execve", 59),
+    ("exit", 60),
+    ("wait4", 61),
+    ("kill", 62),
+    ("getcwd", 79),
+    ("chdir", 80),
+    ("mkdir", 83),
+    ("unlink", 87),
+    ("chmod", 90),
+    ("getuid", 102),
+    ("setuid", 105),
+    ("setgid", 106),
+    ("geteuid", 107),
+    ("getppid", 110),
+    ("setsid", 112),
+    ("setreuid", 113),
+    ("setresuid", 117),
+    ("exit_group", 231),
+    ("openat", 257),
+    ("accept4", 288),
+];
+
+/// System call numbers for ARM EABI.
+///
+/// User and group ID calls use the 32-bit variants (e.g. `setuid32`).
+const ARM_TABLE: &[(&str, u32)] = &[
+    ("exit", 1),
+    ("fork", 2),
+    ("read", 3),
+    ("write", 4),
+    ("open", 5),
+    ("close", 6),
+    ("unlink", 10),
+    ("execve", 11),
+    ("chdir", 12),
+    ("chmod", 15),
+    ("lseek", 19),
+    ("getpid", 20),
+    ("kill", 37),
+    ("mkdir", 39),
+    ("dup", 41),
+    ("pipe", 42),
+    ("brk", 45),
+    ("ioctl", 54),
+    ("dup2", 63),
+    ("getppid", 64),
+    ("setsid", 66),
+    ("munmap", 91),
+    ("wait4", 114),
+    ("clone", 120),
+    ("mprotect", 125),
+    ("nanosleep", 162),
+    ("getcwd", 183),
+    ("mmap2", 192),
+    ("getuid", 199),
+    ("geteuid", 201),
+    ("setreuid", 203),
+    ("setresuid", 208),
+    ("setuid", 213),
+    ("setgid", 214),
+    ("exit_group", 248),
+    ("socket", 281),
+    ("bind", 282),
+    ("connect", 283),
+    ("listen", 284),
+    ("accept", 285),
+    ("sendto", 290),
+    ("recvfrom", 292),
+    ("openat", 322),
+    ("accept4", 366),
+];
+
+/// System call numbers for ARM64.
+const ARM64_TABLE: &[(&str, u32)] = &[
+    ("getcwd", 17),
+    ("dup", 23),
+    ("dup3", 24),
+    ("ioctl", 29),
+    ("mkdirat", 34),
+    ("unlinkat", 35),
+    ("chdir", 49),
+    ("fchmodat", 53),
+    ("openat", 56),
+    ("close", 57),
+    ("pipe2", 59),
+    ("lseek", 62),
+    ("read", 63),
+    ("write", 64),
+    ("exit", 93),
+    ("exit_group", 94),
+    ("nanosleep", 101),
+    ("kill", 129),
+    ("setgid", 144),
+    ("setreuid", 145),
+    ("setuid", 146),
+    ("setresuid", 147),
+    ("setsid", 157),
+    ("getpid", 172),
+    ("getppid", 173),
+    ("getuid", 174),
+    ("geteuid", 175),
+    ("socket", 198),
+    ("bind", 200),
+    ("listen", 201),
+    ("accept", 202),
+    ("connect", 203),
+    ("sendto", 206),
+    ("recvfrom", 207),
+    ("brk", 214),
+    ("munmap", 215),
+    ("clone", 220),
+    ("execve", 221),
+    ("mmap", 222),
+    ("mprotect", 226),
+    ("accept4", 242),
+    ("wait4", 260),
+];
+
+/// System call numbers for MIPS o32.
+const MIPS_TABLE: &[(&str, u32)] = &[
+    ("exit", 4001),
+    ("fork", 4002),
+    ("read", 4003),
+    ("write", 4004),
+    ("open", 4005),
+    ("close", 4006),
+    ("unlink", 4010),
+    ("execve", 4011),
+    ("chdir", 4012),
+    ("chmod", 4015),
+    ("lseek", 4019),
+    ("getpid", 4020),
+    ("setuid", 4023),
+    ("getuid", 4024),
+    ("kill", 4037),
+    ("mkdir", 4039),
+    ("dup", 4041),
+    ("pipe", 4042),
+    ("brk", 4045),
+    ("setgid", 4046),
+    ("geteuid", 4049),
+    ("ioctl", 4054),
+    ("dup2", 4063),
+    ("getppid", 4064),
+    ("setsid", 4066),
+    ("setreuid", 4070),
+    ("mmap", 4090),
+    ("munmap", 4091),
+    ("wait4", 4114),
+    ("clone", 4120),
+    ("mprotect", 4125),
+    ("nanosleep", 4166),
+    ("accept", 4168),
+    ("bind", 4169),
+    ("connect", 4170),
+    ("listen", 4174),
+    ("recvfrom", 4176),
+    ("sendto", 4180),
+    ("socket", 4183),
+    ("setresuid", 4185),
+    ("getcwd", 4203),
+    ("mmap2", 4210),
+    ("exit_group", 4246),
+    ("openat", 4288),
+    ("accept4", 4334),
+];
+
+// -----------------------------------------------------------------------------------------------
+// ABIs
+// -----------------------------------------------------------------------------------------------
+
+/// Linux system call ABIs.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Abi {
+    /// x86, using `int 0x80`.
+    X86,
+    /// x86-64, using `syscall`.
+    X64,
+    /// ARM EABI, using `svc #0`.
+    Arm,
+    /// ARM64, using `svc #0`.
+    Arm64,
+    /// MIPS o32, using `syscall`.
+    Mips,
+}
+
+/// System call argument.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Arg {
+    /// Immediate value.
+    Imm(i64),
+    /// Value of a register.
+    Reg(String),
+}
+
+impl From<i64> for Arg {
+    fn from(value: i64) -> Self {
+        Arg::Imm(value)
+    }
+}
+
+impl From<&str> for Arg {
+    fn from(reg: &str) -> Self {
+        Arg::Reg(reg.to_string())
+    }
+}
+
+impl Abi {
+    /// Returns the ABI matching the configuration of `engine`.
+    ///
+    /// Returns [`ffi::Error::ARCH`] or [`ffi::Error::MODE`] if there is no supported ABI for the
+    /// architecture or the mode of the engine.
+    pub fn from_engine(engine: &Keystone) -> Result<Self> {
+        let mode = engine.mode();
+        match engine.arch() {
+            ffi::Arch::X86 if mode.contains(ffi::Mode::MODE_64) => Ok(Abi::X64),
+            ffi::Arch::X86 if mode.contains(ffi::Mode::MODE_32) => Ok(Abi::X86),
+            ffi::Arch::ARM => Ok(Abi::Arm),
+            ffi::Arch::ARM64 => Ok(Abi::Arm64),
+            ffi::Arch::MIPS if !mode.contains(ffi::Mode::MIPS64) => Ok(Abi::Mips),
+            ffi::Arch::X86 | ffi::Arch::MIPS => Err(ffi::Error::MODE)?,
+            _ => Err(ffi::Error::ARCH)?,
+        }
+    }
+
+    /// Returns the system call table of the ABI.
+    pub fn table(self) -> &'static [(&'static str, u32)] {
+        match self {
+            Abi::X86 => X86_TABLE,
+            Abi::X64 => X64_TABLE,
+            Abi::Arm => ARM_TABLE,
+            Abi::Arm64 => ARM64_TABLE,
+            Abi::Mips => MIPS_TABLE,
+        }
+    }
+
+    /// Returns the number of the system call `name`, if it is known.
+    pub fn number(self, name: &str) -> Option<u32> {
+        self.table()
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, nr)| nr)
+    }
+
+    /// Returns the register holding the system call number.
+    pub fn number_register(self) -> &'static str {
+        match self {
+            Abi::X86 => "eax",
+            Abi::X64 => "rax",
+            Abi::Arm => "r7",
+            Abi::Arm64 => "x8",
+            Abi::Mips => "$v0",
+        }
+    }
+
+    /// Returns the locations of the arguments, in order.
+    ///
+    /// MIPS passes the arguments after the fourth one on the stack.
+    pub fn argument_registers(self) -> &'static [&'static str] {
+        match self {
+            Abi::X86 => &["ebx", "ecx", "edx", "esi", "edi", "ebp"],
+            Abi::X64 => &["rdi", "rsi", "rdx", "r10", "r8", "r9"],
+            Abi::Arm => &["r0", "r1", "r2", "r3", "r4", "r5", "r6"],
+            Abi::Arm64 => &["x0", "x1", "x2", "x3", "x4", "x5"],
+            Abi::Mips => &["$a0", "$a1", "$a2", "$a3", "16($sp)", "20($sp)"],
+        }
+    }
+
+    /// Returns the instruction performing the system call.
+    pub fn instruction(self) -> &'static str {
+        match self {
+            Abi::X86 => "int 0x80",
+            Abi::X64 | Abi::Mips => "syscall",
+            Abi::Arm | Abi::Arm64 => "svc #0",
+        }
+    }
+
+    /// Returns the instructions loading `arg` into `dst`.
+    fn load(self, dst: &str, arg: &Arg) -> Vec<String> {
+        match (self, arg) {
+            (_, Arg::Reg(src)) if src == dst => vec![],
+            (Abi::X86 | Abi::X64, Arg::Imm(v)) => vec![format!("mov {}, {}", dst, v)],
+            (Abi::X86 | Abi::X64 | Abi::Arm | Abi::Arm64, Arg::Reg(src)) => {
+                vec![format!("mov {}, {}", dst, src)]
+            }
+            (Abi::Arm, Arg::Imm(v)) => {
+                let v = *v as u32;
+                let mut insns = vec![format!("movw {}, #{:#x}", dst, v & 0xffff)];
+                if v >> 16 != 0 {
+                    insns.push(format!("movt {}, #{:#x}", dst, v >> 16));
+                }
+                insns
+            }
+            (Abi::Arm64, Arg::Imm(v)) => {
+                let v = *v as u64;
+                let mut insns = vec![format!("movz {}, #{:#x}", dst, v & 0xffff)];
+                for shift in [16, 32, 48] {
+                    let chunk = (v >> shift) & 0xffff;
+                    if chunk != 0 {
+                        insns.push(format!("movk {}, #{:#x}, lsl #{}", dst, chunk, shift));
+                    }
+                }
+                insns
+            }
+            // Stack arguments go through a temporary register.
+            (Abi::Mips, _) if dst.ends_with("($sp)") => {
+                let mut insns = self.load("$t0", arg);
+                insns.push(format!("sw $t0, {}", dst));
+                insns
+            }
+            (Abi::Mips, Arg::Imm(v)) => vec![format!("li {}, {}", dst, *v as i32)],
+            (Abi::Mips, Arg::Reg(src)) => vec![format!("move {}, {}", dst, src)],
+        }
+    }
+
+    /// Returns the assembly of the system call `name` with the arguments `args`.
+    ///
+    /// Returns [`MiscError::UnknownSyscall`] if the system call isn't in the table of the ABI,
+    /// and [`MiscError::SyscallArgs`] if there are more arguments than the ABI supports.
+    pub fn asm(self, name: &str, args: &[Arg]) -> Result<String> {
+        let nr = self.number(name).ok_or(MiscError::UnknownSyscall)?;
+        let regs = self.argument_registers();
+        if args.len() > regs.len() {
+            return Err(MiscError::SyscallArgs)?;
+        }
+        let mut insns = vec![];
+        for (reg, arg) in regs.iter().zip(args) {
+            insns.extend(self.load(reg, arg));
+        }
+        insns.extend(self.load(self.number_register(), &Arg::Imm(nr as i64)));
+        insns.push(self.instruction().to_string());
+        Ok(insns.join("\n"))
+    }
+}
+
+/// Assembles the system call `name` with the arguments `args` for the ABI of `engine`.
+///
+/// x86 engines are expected to use the Intel syntax.
+pub fn assemble(engine: &Keystone, name: &str, args: &[Arg]) -> Result<KeystoneOutput> {
+    let source = Abi::from_engine(engine)?.asm(name, args)?;
+    engine.asm(source, 0)
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asm() {
+        let args = [Arg::Imm(1), Arg::from("rsp"), Arg::Imm(8)];
+        assert_eq!(
+            Abi::X64.asm("write", &args),
+            Ok("mov rdi, 1\nmov rsi, rsp\nmov rdx, 8\nmov rax, 1\nsyscall".to_string())
+        );
+        assert_eq!(
+            Abi::Arm64.asm("exit", &[Arg::Imm(0x10000)]),
+            Ok("movz x0, #0x0\nmovk x0, #0x1, lsl #16\nmovz x8, #0x5d\nsvc #0".to_string())
+        );
+        assert_eq!(
+            Abi::Arm.asm("exit", &[Arg::from("r0")]),
+            Ok("movw r7, #0x1\nsvc #0".to_string())
+        );
+        assert_eq!(
+            Abi::Mips.asm("getpid", &[]),
+            Ok("li $v0, 4020\nsyscall".to_string())
+        );
+        assert_eq!(
+            Abi::X86.asm("nope", &[]),
+            Err(MiscError::UnknownSyscall.into())
+        );
+        assert_eq!(
+            Abi::Arm64.asm("exit", &vec![Arg::Imm(0); 7]),
+            Err(MiscError::SyscallArgs.into())
+        );
+    }
+
+    #[test]
+    fn test_assemble() {
+        let engine = Keystone::new(ffi::Arch::X86, ffi::Mode::MODE_32).unwrap();
+        let output = assemble(&engine, "exit", &[Arg::Imm(0)]).unwrap();
+        assert_eq!(
+            output.bytes,
+            vec![0xbb, 0, 0, 0, 0, 0xb8, 0x01, 0, 0, 0, 0xcd, 0x80]
+        );
+    }
+}
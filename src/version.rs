@@ -0,0 +1,212 @@
+//! Library version and capabilities.
+//!
+//! The bindings support the Keystone API versions they have been tested with, from
+//! [`Version::MIN_SUPPORTED`] to [`Version::MAX_SUPPORTED`]. Version 0.9 covers the 0.9.x
+//! releases as well as builds of the master branch. Features that were added along the way, like
+//! the symbol resolver, are reported by [`Capabilities`], so that callers can check them directly
+//! instead of comparing version numbers.
+//!
+//! ```no_run
+//! use keystone_engine::version::Capabilities;
+//! use keystone_engine::{Arch, OptionValue};
+//!
+//! let caps = Capabilities::detect().expect("Could not detect the Keystone capabilities");
+//! println!("Keystone {}", caps.version);
+//! if caps.supports_arch(Arch::X86) && caps.supports_syntax(OptionValue::SYNTAX_NASM) {
+//!     println!("NASM syntax available");
+//! }
+//! ```
+
+use crate::{ffi, Keystone, Result};
+
+/// Version of the Keystone API.
+///
+/// Keystone only exposes the major and minor numbers of its API through `ks_version`, so there is
+/// no patch number, and e.g. the 0.9.1 and 0.9.2 releases both report version 0.9.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct Version {
+    /// Major version number.
+    pub major: u32,
+    /// Minor version number.
+    pub minor: u32,
+}
+
+impl Version {
+    /// Oldest version supported by the bindings.
+    pub const MIN_SUPPORTED: Version = Version {
+        major: ffi::API_MAJOR,
+        minor: ffi::API_MINOR,
+    };
+
+    /// Newest version supported by the bindings, i.e. the newest one they have been tested with.
+    pub const MAX_SUPPORTED: Version = Version {
+        major: ffi::API_MAJOR,
+        minor: ffi::API_MINOR,
+    };
+
+    /// Creates a new version.
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Version { major, minor }
+    }
+
+    /// Returns the version of the Keystone library.
+    pub fn library() -> Self {
+        let (major, minor) = Keystone::version();
+        Version { major, minor }
+    }
+
+    /// Returns the version as combined by `ks_version`, i.e. `major << 8 | minor`.
+    pub const fn combined(self) -> u32 {
+        self.major << 8 | self.minor
+    }
+
+    /// Returns `true` if the bindings support this version, that is if it is between
+    /// [`Version::MIN_SUPPORTED`] and [`Version::MAX_SUPPORTED`].
+    pub const fn is_supported(self) -> bool {
+        let (min, max) = (Self::MIN_SUPPORTED, Self::MAX_SUPPORTED);
+        (self.major > min.major || self.major == min.major && self.minor >= min.minor)
+            && (self.major < max.major || self.major == max.major && self.minor <= max.minor)
+    }
+
+    /// Describes the versions supported by the bindings, e.g. `0.9` or `0.9 to 0.10`.
+    pub fn supported_range() -> String {
+        if Self::MIN_SUPPORTED == Self::MAX_SUPPORTED {
+            Self::MIN_SUPPORTED.to_string()
+        } else {
+            format!("{} to {}", Self::MIN_SUPPORTED, Self::MAX_SUPPORTED)
+        }
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Architectures known to the bindings, along with a mode valid for each of them.
+const ARCHS: &[(ffi::Arch, ffi::Mode)] = &[
+    (ffi::Arch::ARM, ffi::Mode::ARM),
+    (ffi::Arch::ARM64, ffi::Mode::LITTLE_ENDIAN),
+    (ffi::Arch::MIPS, ffi::Mode::MIPS32),
+    (ffi::Arch::X86, ffi::Mode::MODE_32),
+    (
+        ffi::Arch::PPC,
+        ffi::Mode::PPC32.union(ffi::Mode::BIG_ENDIAN),
+    ),
+    (
+        ffi::Arch::SPARC,
+        ffi::Mode::SPARC32.union(ffi::Mode::BIG_ENDIAN),
+    ),
+    (ffi::Arch::SYSTEMZ, ffi::Mode::BIG_ENDIAN),
+    (ffi::Arch::HEXAGON, ffi::Mode::BIG_ENDIAN),
+    (ffi::Arch::EVM, ffi::Mode::LITTLE_ENDIAN),
+];
+
+/// x86 syntaxes that can be selected with [`ffi::OptionType::SYNTAX`].
+const SYNTAXES: &[ffi::OptionValue] = &[
+    ffi::OptionValue::SYNTAX_INTEL,
+    ffi::OptionValue::SYNTAX_ATT,
+    ffi::OptionValue::SYNTAX_NASM,
+    ffi::OptionValue::SYNTAX_MASM,
+    ffi::OptionValue::SYNTAX_GAS,
+    ffi::OptionValue::SYNTAX_RADIX16,
+];
+
+/// Features available in the Keystone library.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Capabilities {
+    /// Version of the library.
+    pub version: Version,
+    /// Architectures compiled into the library.
+    pub archs: Vec<ffi::Arch>,
+    /// x86 syntaxes accepted by the library, empty if x86 isn't supported.
+    pub syntaxes: ffi::OptionValue,
+    /// Whether the library supports symbol resolvers (see [`Keystone::asm_with_resolver`]).
+    pub resolver: bool,
+}
+
+impl Capabilities {
+    /// Queries the capabilities of the Keystone library.
+    ///
+    /// Returns [`ffi::Error::VERSION`] if the version of the library isn't supported.
+    pub fn detect() -> Result<Self> {
+        Keystone::load()?;
+        let version = Version::library();
+        if !version.is_supported() {
+            return Err(ffi::Error::VERSION)?;
+        }
+        let archs: Vec<_> = ARCHS
+            .iter()
+            .filter(|&&(arch, _)| Keystone::arch_supported(arch))
+            .collect();
+        // A single engine is opened: syntaxes are probed on x86, and the resolver on any
+        // architecture.
+        let engine = archs
+            .iter()
+            .find(|&&&(arch, _)| arch == ffi::Arch::X86)
+            .or(archs.first())
+            .and_then(|&&(arch, mode)| Keystone::new(arch, mode).ok());
+        let mut syntaxes = ffi::OptionValue::empty();
+        if let Some(x86) = engine.as_ref().filter(|e| e.arch() == ffi::Arch::X86) {
+            for &syntax in SYNTAXES {
+                if x86.option(ffi::OptionType::SYNTAX, syntax).is_ok() {
+                    syntaxes |= syntax;
+                }
+            }
+        }
+        let resolver = engine.as_ref().is_some_and(|engine| {
+            engine
+                .option(ffi::OptionType::SYM_RESOLVER, ffi::OptionValue::empty())
+                .is_ok()
+        });
+        Ok(Capabilities {
+            version,
+            archs: archs.iter().map(|&&(arch, _)| arch).collect(),
+            syntaxes,
+            resolver,
+        })
+    }
+
+    /// Returns `true` if `arch` is compiled into the library.
+    pub fn supports_arch(&self, arch: ffi::Arch) -> bool {
+        self.archs.contains(&arch)
+    }
+
+    /// Returns `true` if all the x86 syntaxes in `syntax` are accepted by the library.
+    pub fn supports_syntax(&self, syntax: ffi::OptionValue) -> bool {
+        self.syntaxes.contains(syntax)
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        assert!(Version::new(0, 9).is_supported());
+        assert!(!Version::new(0, 10).is_supported());
+        assert!(!Version::new(0, 8).is_supported());
+        assert!(!Version::new(1, 0).is_supported());
+        assert_eq!(Version::new(0, 9).combined(), 0x009);
+        assert_eq!(Version::new(0, 9).to_string(), "0.9");
+        assert_eq!(Version::supported_range(), "0.9");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::detect().unwrap();
+        assert!(caps.version.is_supported());
+        assert!(caps.resolver);
+        if caps.supports_arch(ffi::Arch::X86) {
+            assert!(
+                caps.supports_syntax(ffi::OptionValue::SYNTAX_INTEL | ffi::OptionValue::SYNTAX_ATT)
+            );
+        }
+    }
+}